@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A receiver's timestamps going back further than this in seconds mean its counter restarted,
+/// smaller steps back are packets arriving late
+const COUNTER_RESET: f64 = 60.0;
+
+/// Time base of one receiver, its timestamps are only compared with each other
+struct SourceTime {
+    /// First timestamp seen from the receiver, in seconds
+    origin_ts: f64,
+    /// Clock reading when the first timestamp was seen
+    origin: Duration,
+    /// Latest timestamp seen from the receiver
+    latest_ts: f64,
+}
+
+struct ClockState {
+    epoch: Instant,
+    sources: HashMap<String, SourceTime>,
+    /// Event time reached so far, once a timestamp was observed
+    latest: Option<Duration>,
+}
+
+/// Time source shared by the whole pipeline, readings are durations since the clock started.
+///
+/// Runs on wall-clock time until a packet carrying a timestamp is observed, from then on it
+/// follows event time so recorded captures age and prune the same way regardless of replay speed.
+/// Receivers keep unrelated time bases (unix seconds, free running MLAT counters), so each one's
+/// timestamps only advance the clock relative to where it stood when the receiver was first seen
+#[derive(Clone)]
+pub struct Clock {
+    state: Arc<Mutex<ClockState>>,
+    follow_events: bool,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState { epoch: Instant::now(), sources: HashMap::new(), latest: None })),
+            follow_events: true,
+        }
+    }
+
+    /// A clock that ignores packet timestamps
+    pub fn wall() -> Self {
+        Self {
+            follow_events: false,
+            ..Self::new()
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        state.latest.unwrap_or_else(|| state.epoch.elapsed())
+    }
+
+    /// Advances event time by how far timestamp `ts` in seconds of `receiver` is past that receiver's first,
+    /// never going backwards. Late packets leave the time as it is, a receiver whose timestamps jump back
+    /// by more than `COUNTER_RESET` is anchored again
+    pub fn observe(&self, receiver: Option<&str>, ts: f64) {
+        if !self.follow_events || !ts.is_finite() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = state.latest.unwrap_or_else(|| state.epoch.elapsed());
+        let receiver = receiver.unwrap_or_default();
+        let source = match state.sources.get_mut(receiver) {
+            Some(source) if ts > source.latest_ts - COUNTER_RESET => source,
+            _ => state.sources.entry(receiver.to_string())
+                .insert_entry(SourceTime { origin_ts: ts, origin: now, latest_ts: ts })
+                .into_mut(),
+        };
+        source.latest_ts = source.latest_ts.max(ts);
+
+        let offset = Duration::try_from_secs_f64(ts - source.origin_ts).unwrap_or(Duration::ZERO);
+        state.latest = Some(now.max(source.origin + offset));
+    }
+
+    /// Restarts the clock from zero, forgetting observed event time. Used when a replay rewinds
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch = Instant::now();
+        state.sources.clear();
+        state.latest = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follows_event_time() {
+        let clock = Clock::new();
+        let start = clock.now();
+
+        clock.observe(None, 1_700_000_000.0);
+        let origin = clock.now();
+        assert!(origin >= start);
+
+        clock.observe(None, 1_700_000_030.5);
+        assert_eq!(origin + Duration::from_millis(30_500), clock.now());
+
+        // late packets don't move time backwards
+        clock.observe(None, 1_700_000_010.0);
+        assert_eq!(origin + Duration::from_millis(30_500), clock.now());
+
+        // clones share the same time
+        assert_eq!(clock.now(), clock.clone().now());
+    }
+
+    #[test]
+    fn test_receivers_keep_their_own_time_base() {
+        let clock = Clock::new();
+        clock.observe(Some("json"), 1_700_000_000.0);
+        let origin = clock.now();
+
+        // a Beast receiver counting seconds since it was powered on
+        clock.observe(Some("beast"), 120.0);
+        assert_eq!(origin, clock.now());
+        clock.observe(Some("beast"), 125.0);
+        assert_eq!(origin + Duration::from_secs(5), clock.now());
+        clock.observe(Some("json"), 1_700_000_006.0);
+        assert_eq!(origin + Duration::from_secs(6), clock.now());
+
+        // a late packet from before its first is ignored
+        clock.observe(Some("beast"), 119.0);
+        clock.observe(Some("beast"), 126.0);
+        assert_eq!(origin + Duration::from_secs(6), clock.now());
+
+        // its counter restarting doesn't stall or rewind the clock
+        clock.observe(Some("beast"), 1.0);
+        clock.observe(Some("beast"), 3.0);
+        assert_eq!(origin + Duration::from_secs(8), clock.now());
+    }
+
+    #[test]
+    fn test_late_packet_keeps_the_anchor() {
+        let clock = Clock::new();
+        clock.observe(Some("rx"), 1_700_000_000.0);
+        let origin = clock.now();
+
+        clock.observe(Some("rx"), 1_699_999_998.0);
+        assert_eq!(origin, clock.now());
+        // later packets advance from the first timestamp, not from the late one
+        clock.observe(Some("rx"), 1_700_000_010.0);
+        assert_eq!(origin + Duration::from_secs(10), clock.now());
+    }
+
+    #[test]
+    fn test_wall_clock_ignores_timestamps() {
+        let clock = Clock::wall();
+        clock.observe(None, 1_700_000_000.0);
+        clock.observe(None, 1_700_009_000.0);
+
+        assert!(clock.now() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_reset_restarts_event_time() {
+        let clock = Clock::new();
+        clock.observe(None, 1_700_000_000.0);
+        clock.observe(None, 1_700_000_600.0);
+        assert!(clock.now() >= Duration::from_secs(600));
+
+        clock.reset();
+        clock.observe(None, 1_700_000_000.0);
+        assert!(clock.now() < Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::filter::config::FilterConfig;
+use crate::simulator::config::ConflictConfig;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Settings loaded from the `--config` TOML file, command line flags override them
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub filter: FilterConfig,
+    pub conflict: ConflictConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        toml::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        self.filter.validate()
+            .and_then(|_| self.conflict.validate())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("[filter]\nthreshold = 5\ntarget_fpr = 0.0001\n").unwrap();
+        assert_eq!(5, config.filter.threshold);
+        assert_eq!(0.0001, config.filter.target_fpr);
+        assert_eq!(FilterConfig::default().partition_size, config.filter.partition_size);
+
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        assert!(Config::parse("[filter]\nthreshhold = 5\n").is_err());
+        assert!(Config::parse("[filter]\ntarget_fpr = 2.0\n").unwrap().validate().is_err());
+
+        let config = Config::parse("[conflict]\nhorizontal_radius = 9260.0\n").unwrap();
+        assert_eq!(9260.0, config.conflict.horizontal_radius);
+        assert_eq!(ConflictConfig::default().vertical_band, config.conflict.vertical_band);
+        assert!(Config::parse("[conflict]\nvertical_band = -1.0\n").unwrap().validate().is_err());
+    }
+}
@@ -1,63 +1,251 @@
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::time::{Duration, Instant};
+use crate::clock::Clock;
+use crate::filter::config::{FilterBackend, FilterConfig};
+use crate::filter::hash::{key_bytes, Hash128, Murmur3};
+use crate::filter::membership::{invalid_state, layer_bits, read_bytes, read_magic, read_u32, read_u64, read_u8, MembershipFilter, MembershipStats, MAX_LAYER_BITS};
+use std::hash::Hash;
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
 
-pub struct ScalableBloomFilter {
+const STATE_MAGIC: &[u8; 6] = b"ACTSBF";
+const STATE_VERSION: u8 = 4;
+/// State flag marking layers that carry counters
+const STATE_COUNTING: u8 = 1;
+/// Counters stick once saturated, decrementing them could drop other IDs sharing the slot
+const COUNTER_MAX: u8 = 15;
+
+pub struct ScalableBloomFilter<H: Hash128 = Murmur3> {
     pub filters: Vec<BloomFilter>,
-    initial_size: usize,
+    hasher: H,
+    /// Base seed, every layer hashes with its own seed derived from it
+    seed: u32,
+    /// Layers keep a counter per slot so IDs can be removed
+    counting: bool,
+    clock: Clock,
     initial_hashes: usize,
+    /// FPR of a generation's first layer
+    initial_fpr: f64,
     target_fpr: f64,
     growth_factor: usize,
     tightening_ratio: f64,
+    /// Bits per partition of a generation's first layer, grown layers scale it by `growth_factor`
     partition_size: usize
 }
 
-impl ScalableBloomFilter {
-    pub fn new() -> Self {
-        let target_fpr = 0.001f64;
-        let initial_hashes = -target_fpr.log2().ceil() as usize;
-        let partition_size = 2048;
-        let initial_size = partition_size * initial_hashes;
-        Self {
-            filters: vec![BloomFilter::new(initial_size, initial_hashes, 1, partition_size)],
-            initial_size,
+/// Hashes needed to reach `fpr` once partitions are half full
+pub fn hashes_for(fpr: f64) -> usize {
+    (-fpr.log2()).ceil() as usize
+}
+
+/// Hash of `key` in layer `layer`, every layer hashes with its own seed derived from the base `seed`
+pub fn layer_hash<H: Hash128>(hasher: &H, seed: u32, key: &[u8], layer: usize) -> (u64, u64) {
+    hasher.hash128(key, seed.wrapping_add(layer as u32))
+}
+
+/// Bit of `partition` for a key, derived from its 128-bit hash by double hashing
+pub fn partition_index((h1, h2): (u64, u64), partition: usize, partition_size: usize) -> usize {
+    let h = h1.wrapping_add((partition as u64).wrapping_mul(h2));
+    partition * partition_size + (h % partition_size as u64) as usize
+}
+
+impl<H: Hash128> ScalableBloomFilter<H> {
+    fn layer_hash(&self, key: &[u8], filter: &BloomFilter) -> (u64, u64) {
+        layer_hash(&self.hasher, self.seed, key, filter.layer)
+    }
+
+    fn new_layer(&self, hashes: usize, partition_size: usize, layer: usize) -> BloomFilter {
+        let now = self.clock.now();
+        let filter = BloomFilter {
+            timestamp: now,
+            written: now,
+            ..BloomFilter::new(partition_size * hashes, hashes, layer, partition_size)
+        };
+        if self.counting { filter.with_counters() } else { filter }
+    }
+
+    /// Layer added once the newest fills up, it belongs to the same generation
+    fn grow(&mut self) {
+        self.target_fpr *= self.tightening_ratio;
+        let hashes = hashes_for(self.target_fpr);
+        let newest = self.filters.last().unwrap();
+        let filter = BloomFilter {
+            timestamp: newest.timestamp,
+            carried_age: newest.carried_age,
+            ..self.new_layer(hashes, newest.partition_size * self.growth_factor, newest.layer + 1)
+        };
+        self.filters.push(filter);
+    }
+}
+
+impl<H: Hash128> MembershipFilter for ScalableBloomFilter<H> {
+    fn new(config: &FilterConfig, clock: Clock) -> Self {
+        let target_fpr = config.target_fpr;
+        let initial_hashes = hashes_for(target_fpr);
+        let partition_size = config.partition_size;
+        let mut sbf = Self {
+            filters: Vec::new(),
+            hasher: H::default(),
+            seed: config.hash_seed,
+            counting: config.backend == FilterBackend::Counting,
+            clock,
+            initial_fpr: target_fpr,
             target_fpr,
             initial_hashes,
-            growth_factor: 2,
-            tightening_ratio: 0.8,
+            growth_factor: config.growth_factor,
+            tightening_ratio: config.tightening_ratio,
             partition_size
-        }
+        };
+        sbf.filters.push(sbf.new_layer(initial_hashes, partition_size, 1));
+        sbf
     }
 
-    pub fn fpr(&self) -> f64 {
+    fn fpr(&self) -> f64 {
         1.0 - self.filters.iter()
             .map(|bf| 1.0 - 0.5f64.powi(bf.hashes as i32))
             .product::<f64>()
     }
 
-    pub fn contains<T: Hash>(&self, input: &T) -> bool {
-        self.filters.iter().any(|f| f.contains(input))
+    fn stats(&self) -> MembershipStats {
+        let total_bits = self.filters.iter().map(|l| l.size).sum();
+        let set_bits = self.filters.iter().map(|l| l.bits.iter().map(|v| v.count_ones()).sum::<u32>()).sum::<u32>() as usize;
+        MembershipStats {
+            backend: if self.counting { "counting" } else { "bloom" },
+            layer_count: self.filters.len(),
+            total_bits,
+            fill_ratio: set_bits as f64 / total_bits as f64,
+            layer_fill: self.filters.iter().map(BloomFilter::fill_ratio).collect(),
+            est_fpr: self.fpr(),
+        }
     }
 
-    pub fn insert<T: Hash>(&mut self, input: &T) {
-        if let Some(filter) = self.filters.last() {
-            if filter.bits.iter().map(|b| b.count_ones()).sum::<u32>() as f64/filter.size as f64 > 0.5 {
-                self.target_fpr *= self.tightening_ratio;
-                let hashes = -self.target_fpr.log2().ceil() as usize;
-                self.filters.push(BloomFilter::new(self.partition_size * hashes * self.growth_factor, hashes , self.filters.len() + 1, self.partition_size))
-            }
+    fn contains<T: Hash>(&self, input: &T) -> bool {
+        let key = key_bytes(input);
+        self.filters.iter().any(|f| f.contains_hash(self.layer_hash(&key, f)))
+    }
+
+    fn insert<T: Hash>(&mut self, input: &T) {
+        let key = key_bytes(input);
+        let newest = self.filters.len() - 1;
+        self.filters[newest].touch(self.clock.now());
+        if self.filters[newest].contains_hash(self.layer_hash(&key, &self.filters[newest])) {
+            return;
+        }
+
+        if self.filters.last().is_some_and(|filter| filter.fill_ratio() > 0.5) {
+            self.grow();
         }
         let last = self.filters.len() - 1;
-        self.filters[last].insert(input);
+        let hash = self.layer_hash(&key, &self.filters[last]);
+        self.filters[last].insert_hash(hash);
+    }
+
+    /// Forgets `input` in every layer holding it, only counting layers can
+    fn remove<T: Hash>(&mut self, input: &T) -> bool {
+        if !self.counting {
+            return false;
+        }
+        let key = key_bytes(input);
+        let mut removed = false;
+        for i in 0..self.filters.len() {
+            let hash = self.layer_hash(&key, &self.filters[i]);
+            removed |= self.filters[i].remove_hash(hash);
+        }
+        removed
+    }
+
+    fn rotate(&mut self) {
+        self.target_fpr = self.initial_fpr;
+        let layer = self.filters.last().map_or(1, |f| f.layer + 1);
+        self.filters.push(self.new_layer(self.initial_hashes, self.partition_size, layer));
+    }
+
+    fn generation_age(&self) -> Duration {
+        self.filters.last().map_or(Duration::ZERO, |f| f.age(self.clock.now()))
     }
 
-    pub fn prune(&mut self, max_age: Duration) {
-        let now = Instant::now();
-        self.filters.retain(|f| now.duration_since(f.timestamp) < max_age);
+    fn prune(&mut self, max_age: Duration) {
+        let now = self.clock.now();
+        self.filters.retain(|f| f.idle(now) < max_age);
         if self.filters.is_empty() {
-            self.filters.push(BloomFilter::new(self.initial_size, self.initial_hashes, 1, self.partition_size))
+            self.rotate();
+        }
+    }
+
+    /// Writes the layers and their ages, all integers little-endian: magic, version, hash id, flags, seed, target FPR,
+    /// layer count, then per layer size, hashes, layer, partition size, generation age and idle time in ms, bits and
+    /// counters if counting
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let now = self.clock.now();
+        writer.write_all(STATE_MAGIC)?;
+        writer.write_all(&[STATE_VERSION, H::ID, if self.counting { STATE_COUNTING } else { 0 }])?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&self.target_fpr.to_le_bytes())?;
+        writer.write_all(&(self.filters.len() as u32).to_le_bytes())?;
+        for filter in &self.filters {
+            writer.write_all(&(filter.size as u64).to_le_bytes())?;
+            writer.write_all(&(filter.hashes as u32).to_le_bytes())?;
+            writer.write_all(&(filter.layer as u32).to_le_bytes())?;
+            writer.write_all(&(filter.partition_size as u64).to_le_bytes())?;
+            writer.write_all(&(filter.age(now).as_millis() as u64).to_le_bytes())?;
+            writer.write_all(&(filter.idle(now).as_millis() as u64).to_le_bytes())?;
+            writer.write_all(&filter.bits)?;
+            if let Some(counters) = &filter.counters {
+                writer.write_all(counters)?;
+            }
         }
+        Ok(())
     }
 
+    /// Restores layers written by `write_to` along with their seed, layers added later use `config`
+    fn read_from<R: Read>(reader: &mut R, config: &FilterConfig, clock: Clock) -> io::Result<Self> {
+        read_magic(reader, STATE_MAGIC, STATE_VERSION)?;
+        let hash = read_u8(reader)?;
+        if hash != H::ID {
+            return Err(invalid_state(&format!("written with hash {hash}, expected {}", H::ID)));
+        }
+        let counting = read_u8(reader)? & STATE_COUNTING != 0;
+        if counting != (config.backend == FilterBackend::Counting) {
+            let saved = if counting { FilterBackend::Counting } else { FilterBackend::Bloom };
+            return Err(invalid_state(&format!("saved by the {saved:?} backend, configured {:?}", config.backend)));
+        }
+
+        let mut sbf = Self::new(config, clock);
+        sbf.seed = read_u32(reader)?;
+        sbf.target_fpr = f64::from_bits(read_u64(reader)?);
+        let now = sbf.clock.now();
+
+        let count = read_u32(reader)?;
+        if count == 0 {
+            return Err(invalid_state("no layers"));
+        }
+        sbf.filters = (0..count).map(|_| {
+            let size = read_u64(reader)? as usize;
+            let hashes = read_u32(reader)? as usize;
+            let layer = read_u32(reader)? as usize;
+            let partition_size = read_u64(reader)? as usize;
+            if layer_bits(hashes, partition_size)? > size || size > MAX_LAYER_BITS {
+                return Err(invalid_state(&format!("layer of {size} bits doesn't fit {hashes} partitions of {partition_size}")));
+            }
+            let age = Duration::from_millis(read_u64(reader)?);
+            let idle = Duration::from_millis(read_u64(reader)?);
+
+            let mut filter = BloomFilter {
+                bits: read_bytes(reader, size.div_ceil(8))?,
+                size,
+                ..BloomFilter::new(0, hashes, layer, partition_size)
+            };
+            if counting {
+                filter.counters = Some(read_bytes(reader, size.div_ceil(2))?);
+            }
+            filter.timestamp = now;
+            filter.carried_age = age;
+            filter.written = now;
+            filter.carried_idle = idle;
+            Ok(filter)
+        }).collect::<io::Result<_>>()?;
+
+        Ok(sbf)
+    }
 }
 
 pub struct BloomFilter {
@@ -65,8 +253,17 @@ pub struct BloomFilter {
     pub size: usize,
     hashes: usize,
     layer: usize,
-    timestamp: Instant,
-    partition_size: usize
+    /// Start of the layer's generation, as read from the owning filter's clock
+    timestamp: Duration,
+    /// Age the layer already had when it was restored from a saved state
+    carried_age: Duration,
+    /// Last time an ID was inserted, or refreshed while already held
+    written: Duration,
+    /// Idle time the layer already had when it was restored
+    carried_idle: Duration,
+    partition_size: usize,
+    /// 4-bit saturating counter per slot, two to a byte, for layers that support removal
+    counters: Option<Vec<u8>>,
 }
 
 impl BloomFilter {
@@ -76,30 +273,87 @@ impl BloomFilter {
             size,
             hashes,
             layer,
-            timestamp: Instant::now(),
+            timestamp: Duration::ZERO,
+            carried_age: Duration::ZERO,
+            written: Duration::ZERO,
+            carried_idle: Duration::ZERO,
             partition_size,
+            counters: None,
         }
     }
 
-    fn hash<T: Hash>(&self, input: &T, partition: usize) -> usize {
-        let mut hasher = DefaultHasher::new();
-        input.hash(&mut hasher);
-        partition.hash(&mut hasher);
-        self.layer.hash(&mut hasher);
-        partition * self.partition_size +  hasher.finish() as usize % self.partition_size
+    pub fn with_counters(self) -> Self {
+        Self {
+            counters: Some(vec![0; self.size.div_ceil(2)]),
+            ..self
+        }
+    }
+
+    fn counter(counters: &[u8], idx: usize) -> u8 {
+        (counters[idx >> 1] >> ((idx & 1) * 4)) & 0xF
+    }
+
+    fn set_counter(counters: &mut [u8], idx: usize, value: u8) {
+        let shift = (idx & 1) * 4;
+        counters[idx >> 1] = (counters[idx >> 1] & !(0xF << shift)) | (value << shift);
     }
 
-    pub fn insert<T: Hash>(&mut self, input: &T) {
+    /// Time since the layer's generation started
+    pub fn age(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.timestamp) + self.carried_age
+    }
+
+    fn touch(&mut self, now: Duration) {
+        self.written = now;
+        self.carried_idle = Duration::ZERO;
+    }
+
+    /// Share of bits set
+    pub fn fill_ratio(&self) -> f64 {
+        self.bits.iter().map(|b| b.count_ones()).sum::<u32>() as f64 / self.size as f64
+    }
+
+    /// Time since an ID was last written to the layer
+    pub fn idle(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.written) + self.carried_idle
+    }
+
+    pub fn insert_hash(&mut self, hash: (u64, u64)) {
         for i in 0..self.hashes {
-            let idx = self.hash(input, i);
+            let idx = partition_index(hash, i, self.partition_size);
             self.bits[idx >> 3] |= 1 << (idx & 7);
+            if let Some(counters) = &mut self.counters {
+                let count = Self::counter(counters, idx);
+                Self::set_counter(counters, idx, (count + 1).min(COUNTER_MAX));
+            }
         }
 
     }
 
-    pub fn contains<T: Hash>(&self, input: &T) -> bool {
+    /// Decrements the key's counters, clearing slots that drop to zero. Only held keys are removed,
+    /// decrementing on a false positive would evict other keys
+    pub fn remove_hash(&mut self, hash: (u64, u64)) -> bool {
+        if self.counters.is_none() || !self.contains_hash(hash) {
+            return false;
+        }
+        for i in 0..self.hashes {
+            let idx = partition_index(hash, i, self.partition_size);
+            let counters = self.counters.as_mut().unwrap();
+            let count = Self::counter(counters, idx);
+            if count == COUNTER_MAX {
+                continue;
+            }
+            Self::set_counter(counters, idx, count.saturating_sub(1));
+            if count <= 1 {
+                self.bits[idx >> 3] &= !(1 << (idx & 7));
+            }
+        }
+        true
+    }
+
+    pub fn contains_hash(&self, hash: (u64, u64)) -> bool {
         (0..self.hashes).all(|i| {
-            let idx = self.hash(input, i);
+            let idx = partition_index(hash, i, self.partition_size);
             self.bits[idx >> 3] & (1 << (idx & 7)) != 0
         })
     }
@@ -108,69 +362,207 @@ impl BloomFilter {
 
 #[cfg(test)]
 mod tests {
-    use rand::distr::{Alphanumeric, SampleString};
+    use crate::test_util::gen_input;
     use super::*;
 
-    fn gen_input(n: usize) -> Vec<String> {
-        let mut rng = rand::rng();
-        (0..n).map(|_| Alphanumeric.sample_string(&mut rng, 8)).collect()
+    fn hashed(input: &String) -> (u64, u64) {
+        Murmur3.hash128(&key_bytes(input), 1)
+    }
+
+    /// Stand-in for an alternative hash, derives both halves from one 64-bit FNV-1a
+    #[derive(Default)]
+    struct Fnv;
+
+    impl Hash128 for Fnv {
+        const ID: u8 = 200;
+
+        fn hash128(&self, data: &[u8], seed: u32) -> (u64, u64) {
+            let h = data.iter().fold(0xcbf2_9ce4_8422_2325 ^ seed as u64, |h, b| (h ^ *b as u64).wrapping_mul(0x100_0000_01b3));
+            (h, h.rotate_left(32) | 1)
+        }
     }
 
     #[test]
     fn test_bf_positive() {
         let mut bf = BloomFilter::new(1024, 4, 1, 256);
-        let input = gen_input(64);
-        input.iter().for_each(|i| bf.insert(i));
+        let input = gen_input(64, 1);
+        input.iter().for_each(|i| bf.insert_hash(hashed(i)));
 
         for i in input {
-            assert_eq!(true, bf.contains(&i), "input {i}");
+            assert!(bf.contains_hash(hashed(&i)), "input {i}");
         }
     }
 
     #[test]
     fn test_bf_negative() {
         let mut bf = BloomFilter::new(4096, 16, 1, 256);
-        let input = gen_input(64);
-        input.iter().for_each(|i| bf.insert(i));
+        let input = gen_input(64, 1);
+        input.iter().for_each(|i| bf.insert_hash(hashed(i)));
 
-        let neg_input = gen_input(16);
+        let neg_input = gen_input(16, 2);
         for i in neg_input {
-            assert_eq!(false, bf.contains(&i), "input {i}");
+            assert!(!bf.contains_hash(hashed(&i)), "input {i}");
         }
     }
 
     #[test]
     fn test_sbf_positive() {
-        let mut bf = ScalableBloomFilter::new();
-        let input = gen_input(2048);
+        let mut bf: ScalableBloomFilter = ScalableBloomFilter::new(&FilterConfig::default(), Clock::new());
+        let input = gen_input(2048, 1);
         input.iter().for_each(|i| bf.insert(i));
 
         for i in input {
             assert!(bf.contains(&i), "input {i}");
         }
-        assert!(bf.filters.len() > 1)
+        assert!(bf.filters.len() > 1);
+        let stats = bf.stats();
+        assert!(stats.layer_fill.iter().all(|f| *f <= stats.fill_ratio * 2.0), "fill {} of layers {:?}", stats.fill_ratio, stats.layer_fill);
     }
 
     #[test]
     fn test_sbf_negative() {
-        let mut bf = ScalableBloomFilter::new();
-        let input = gen_input(2048);
+        let mut bf: ScalableBloomFilter = ScalableBloomFilter::new(&FilterConfig::default(), Clock::new());
+        let input = gen_input(2048, 1);
         input.iter().for_each(|i| bf.insert(i));
 
-        let neg_input = gen_input(1);
+        let neg_input = gen_input(1, 2);
         for i in neg_input {
-            assert_eq!(false, bf.contains(&i), "input {i}");
+            assert!(!bf.contains(&i), "input {i}");
         }
         assert!(bf.filters.len() > 1)
     }
 
+    #[test]
+    fn test_sbf_state_roundtrip() {
+        let clock = Clock::new();
+        clock.observe(None, 0.0);
+        let config = FilterConfig { hash_seed: 42, ..FilterConfig::default() };
+        let mut bf: ScalableBloomFilter = ScalableBloomFilter::new(&config, clock.clone());
+        let input = gen_input(2048, 1);
+        input.iter().for_each(|i| bf.insert(i));
+        clock.observe(None, 60.0);
+
+        let mut state = Vec::new();
+        bf.write_to(&mut state).unwrap();
+
+        let restarted = Clock::new();
+        let mut restored: ScalableBloomFilter = ScalableBloomFilter::read_from(&mut state.as_slice(), &FilterConfig::default(), restarted.clone()).unwrap();
+        assert_eq!(42, restored.seed);
+        assert_eq!(bf.filters.len(), restored.filters.len());
+        assert_eq!(bf.target_fpr, restored.target_fpr);
+        for i in &input {
+            assert!(restored.contains(i), "input {i}");
+        }
+
+        // restored layers keep their age
+        restarted.observe(None, 0.0);
+        restored.prune(Duration::from_secs(61));
+        assert!(!restored.filters.is_empty() && restored.contains(&input[0]));
+        restarted.observe(None, 2.0);
+        restored.prune(Duration::from_secs(61));
+        assert!(!restored.contains(&input[0]));
+
+        // a state written with another hash can't be read back
+        assert!(ScalableBloomFilter::<Fnv>::read_from(&mut state.as_slice(), &FilterConfig::default(), Clock::new()).is_err());
+        assert!(ScalableBloomFilter::<Murmur3>::read_from(&mut &state[..20], &FilterConfig::default(), Clock::new()).is_err());
+        state[6] = STATE_VERSION + 1;
+        assert!(ScalableBloomFilter::<Murmur3>::read_from(&mut state.as_slice(), &FilterConfig::default(), Clock::new()).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_state() {
+        let bf: ScalableBloomFilter = ScalableBloomFilter::new(&FilterConfig::default(), Clock::new());
+        let mut state = Vec::new();
+        bf.write_to(&mut state).unwrap();
+        let read = |state: &[u8]| ScalableBloomFilter::<Murmur3>::read_from(&mut &state[..], &FilterConfig::default(), Clock::new());
+        assert!(read(&state).is_ok());
+
+        // first layer's size, hash count and partition size
+        let mut huge = state.clone();
+        huge[25..33].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&huge).is_err());
+        let mut overflowing = state.clone();
+        overflowing[33..37].copy_from_slice(&u32::MAX.to_le_bytes());
+        overflowing[41..49].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&overflowing).is_err());
+        let mut truncated = state.clone();
+        truncated[25..33].copy_from_slice(&(MAX_LAYER_BITS as u64).to_le_bytes());
+        assert_eq!(io::ErrorKind::UnexpectedEof, read(&truncated).err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_reproducible_contents() {
+        let build = |seed| {
+            let config = FilterConfig { hash_seed: seed, ..FilterConfig::default() };
+            let mut bf: ScalableBloomFilter = ScalableBloomFilter::new(&config, Clock::new());
+            gen_input(64, 1).iter().for_each(|i| bf.insert(i));
+            bf.filters[0].bits.clone()
+        };
+
+        assert_eq!(build(7), build(7));
+        assert_ne!(build(7), build(8));
+    }
+
+    #[test]
+    fn test_pluggable_hasher() {
+        let mut bf: ScalableBloomFilter<Fnv> = ScalableBloomFilter::new(&FilterConfig::default(), Clock::new());
+        let input = gen_input(256, 1);
+        input.iter().for_each(|i| bf.insert(i));
+
+        for i in input {
+            assert!(bf.contains(&i), "input {i}");
+        }
+    }
+
+    #[test]
+    fn test_counting_remove() {
+        let mut bf = BloomFilter::new(1024, 4, 1, 256).with_counters();
+        let input = gen_input(64, 1);
+        input.iter().for_each(|i| bf.insert_hash(hashed(i)));
+
+        assert!(bf.remove_hash(hashed(&input[0])));
+        assert!(!bf.contains_hash(hashed(&input[0])));
+        assert!(!bf.remove_hash(hashed(&input[0])));
+        for i in &input[1..] {
+            assert!(bf.contains_hash(hashed(i)), "input {i}");
+        }
+
+        // saturated counters are never decremented
+        let mut bf = BloomFilter::new(128, 4, 1, 32).with_counters();
+        (0..20).for_each(|_| bf.insert_hash(hashed(&input[0])));
+        assert!(bf.remove_hash(hashed(&input[0])));
+        assert!(bf.contains_hash(hashed(&input[0])));
+
+        // plain layers can't remove
+        let mut bf = BloomFilter::new(1024, 4, 1, 256);
+        bf.insert_hash(hashed(&input[0]));
+        assert!(!bf.remove_hash(hashed(&input[0])));
+    }
+
+    #[test]
+    fn test_counting_state_roundtrip() {
+        let config = FilterConfig { backend: FilterBackend::Counting, ..FilterConfig::default() };
+        let mut bf: ScalableBloomFilter = ScalableBloomFilter::new(&config, Clock::new());
+        let input = gen_input(2048, 1);
+        input.iter().for_each(|i| bf.insert(i));
+
+        let mut state = Vec::new();
+        bf.write_to(&mut state).unwrap();
+        assert!(ScalableBloomFilter::<Murmur3>::read_from(&mut state.as_slice(), &FilterConfig::default(), Clock::new()).is_err());
+
+        let mut restored: ScalableBloomFilter = ScalableBloomFilter::read_from(&mut state.as_slice(), &config, Clock::new()).unwrap();
+        assert!(restored.remove(&input[0]));
+        assert!(!restored.contains(&input[0]));
+        assert!(input[1..].iter().all(|i| restored.contains(i)));
+    }
+
     #[test]
     fn partitioning() {
-        let input = gen_input(256);
+        let input = gen_input(256, 1);
         for i in input {
             let mut bf = BloomFilter::new(128, 4, 1, 32);
-            bf.insert(&i);
-            assert!(bf.contains(&i), "input {i}");
+            bf.insert_hash(hashed(&i));
+            assert!(bf.contains_hash(hashed(&i)), "input {i}");
             assert_eq!(4, bf.bits.iter().map(|x| x.count_ones()).sum::<u32>())
         }
     }
@@ -0,0 +1,549 @@
+use crate::clock::Clock;
+use crate::filter::bloom_filter::{hashes_for, layer_hash, partition_index};
+use crate::filter::config::FilterConfig;
+use crate::filter::hash::{key_bytes, Hash128, Murmur3};
+use crate::filter::membership::{invalid_state, layer_bits, read_bytes, read_magic, read_u32, read_u64, read_u8, MembershipFilter, MembershipStats, SharedFilter};
+use std::hash::Hash;
+use std::io;
+use std::io::{Read, Write};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const STATE_MAGIC: &[u8; 6] = b"ACTCBF";
+const STATE_VERSION: u8 = 1;
+
+fn millis(t: Duration) -> i64 {
+    t.as_millis() as i64
+}
+
+/// Layer of atomic bit words, bits are only ever set so readers and writers never wait on each other. Ages are
+/// kept as in `BloomFilter`
+pub struct AtomicLayer {
+    words: Vec<AtomicU64>,
+    /// Bits set so far, tracked as they flip
+    set_bits: AtomicUsize,
+    size: usize,
+    hashes: usize,
+    layer: usize,
+    partition_size: usize,
+    timestamp: Duration,
+    carried_age: Duration,
+    /// Clock reading in ms the layer is idle since, below zero for idle time carried over from a saved state
+    idle_since: AtomicI64,
+    /// Set by inserts, folded into `idle_since` on prune so the hot path never reads the clock
+    touched: AtomicBool,
+}
+
+impl AtomicLayer {
+    fn new(hashes: usize, partition_size: usize, layer: usize, timestamp: Duration) -> Self {
+        let size = hashes * partition_size;
+        Self {
+            words: (0..size.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            set_bits: AtomicUsize::new(0),
+            size,
+            hashes,
+            layer,
+            partition_size,
+            timestamp,
+            carried_age: Duration::ZERO,
+            idle_since: AtomicI64::new(millis(timestamp)),
+            touched: AtomicBool::new(false),
+        }
+    }
+
+    fn age(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.timestamp) + self.carried_age
+    }
+
+    fn idle(&self, now: Duration) -> Duration {
+        Duration::from_millis((millis(now) - self.idle_since.load(Ordering::Relaxed)).max(0) as u64)
+    }
+
+    fn fill_ratio(&self) -> f64 {
+        self.set_bits.load(Ordering::Relaxed) as f64 / self.size as f64
+    }
+
+    fn insert_hash(&self, hash: (u64, u64)) {
+        for i in 0..self.hashes {
+            let idx = partition_index(hash, i, self.partition_size);
+            let bit = 1 << (idx & 63);
+            if self.words[idx >> 6].fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                self.set_bits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn contains_hash(&self, hash: (u64, u64)) -> bool {
+        (0..self.hashes).all(|i| {
+            let idx = partition_index(hash, i, self.partition_size);
+            self.words[idx >> 6].load(Ordering::Relaxed) & (1 << (idx & 63)) != 0
+        })
+    }
+}
+
+/// Layers and the FPR the next grown layer targets, never changed once published
+struct Layers {
+    filters: Vec<Arc<AtomicLayer>>,
+    target_fpr: f64,
+}
+
+/// Readers that can hold a snapshot at once, more wait for a slot to free up
+const HAZARD_SLOTS: usize = 64;
+
+/// Current `Layers`, read without taking a lock. Every reader announces the snapshot it holds in a hazard slot, a
+/// replaced snapshot is freed as soon as no slot holds it
+struct Published {
+    current: AtomicPtr<Layers>,
+    hazards: [AtomicPtr<Layers>; HAZARD_SLOTS],
+    /// Replaced snapshots still held by a reader, the lock also serialises updates
+    retired: Mutex<Vec<Arc<Layers>>>,
+}
+
+impl Published {
+    fn new(layers: Layers) -> Self {
+        Self {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(layers)).cast_mut()),
+            hazards: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&Layers) -> R) -> R {
+        let (hazard, layers) = self.protect();
+        // SAFETY: `layers` was still current after it was announced in `hazard`, so any later update replacing it
+        // sees the announcement and keeps it until the slot is cleared
+        let result = f(unsafe { &*layers });
+        hazard.store(ptr::null_mut(), Ordering::SeqCst);
+        result
+    }
+
+    /// Claims a free hazard slot announcing the current snapshot
+    fn protect(&self) -> (&AtomicPtr<Layers>, *mut Layers) {
+        loop {
+            let mut layers = self.current.load(Ordering::SeqCst);
+            let claimed = self.hazards.iter().find(|hazard| {
+                hazard.compare_exchange(ptr::null_mut(), layers, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+            });
+            let Some(hazard) = claimed else {
+                thread::yield_now();
+                continue;
+            };
+            // replaced before the announcement was seen, announce the new one
+            loop {
+                let current = self.current.load(Ordering::SeqCst);
+                if current == layers {
+                    return (hazard, layers);
+                }
+                layers = current;
+                hazard.store(layers, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Publishes what `f` makes of the current layers, if anything. Updates run one at a time
+    fn update(&self, f: impl FnOnce(&Layers) -> Option<Layers>) {
+        let mut retired = self.retired.lock().unwrap();
+        // SAFETY: only updates replace the snapshot and they hold the lock, so it can't be freed meanwhile
+        let current = unsafe { &*self.current.load(Ordering::SeqCst) };
+        if let Some(layers) = f(current) {
+            let old = self.current.swap(Arc::into_raw(Arc::new(layers)).cast_mut(), Ordering::SeqCst);
+            // SAFETY: `old` came from `Arc::into_raw` and is no longer reachable through `current`
+            retired.push(unsafe { Arc::from_raw(old) });
+        }
+        retired.retain(|layers| {
+            let layers = Arc::as_ptr(layers).cast_mut();
+            self.hazards.iter().any(|hazard| hazard.load(Ordering::SeqCst) == layers)
+        });
+    }
+}
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        // SAFETY: no reader is left once the last handle is dropped
+        drop(unsafe { Arc::from_raw(*self.current.get_mut()) });
+    }
+}
+
+/// Scalable Bloom filter that can be shared between threads, clones are handles to the same filter. Configured
+/// like `ScalableBloomFilter`.
+///
+/// `contains` and `insert` read the published layers without locking and set bits atomically, a lookup racing an
+/// insert of the same key may miss it. Growth, rotation and pruning publish a new set of layers, one at a time
+pub struct ConcurrentBloomFilter<H: Hash128 = Murmur3> {
+    layers: Arc<Published>,
+    hasher: H,
+    seed: u32,
+    clock: Clock,
+    initial_hashes: usize,
+    initial_fpr: f64,
+    growth_factor: usize,
+    tightening_ratio: f64,
+    partition_size: usize,
+}
+
+impl<H: Hash128 + Clone> Clone for ConcurrentBloomFilter<H> {
+    fn clone(&self) -> Self {
+        Self {
+            layers: self.layers.clone(),
+            hasher: self.hasher.clone(),
+            clock: self.clock.clone(),
+            ..*self
+        }
+    }
+}
+
+impl<H: Hash128> ConcurrentBloomFilter<H> {
+    fn from_layers(config: &FilterConfig, seed: u32, clock: Clock, layers: Layers) -> Self {
+        Self {
+            layers: Arc::new(Published::new(layers)),
+            hasher: H::default(),
+            seed,
+            clock,
+            initial_hashes: hashes_for(config.target_fpr),
+            initial_fpr: config.target_fpr,
+            growth_factor: config.growth_factor,
+            tightening_ratio: config.tightening_ratio,
+            partition_size: config.partition_size,
+        }
+    }
+
+    fn layer_hash(&self, key: &[u8], filter: &AtomicLayer) -> (u64, u64) {
+        layer_hash(&self.hasher, self.seed, key, filter.layer)
+    }
+
+    /// Layers with one added once the newest fills up, it belongs to the same generation
+    fn grow(&self, layers: &Layers) -> Layers {
+        let target_fpr = layers.target_fpr * self.tightening_ratio;
+        let newest = layers.filters.last().unwrap();
+        let filter = AtomicLayer {
+            carried_age: newest.carried_age,
+            ..AtomicLayer::new(
+                hashes_for(target_fpr),
+                newest.partition_size * self.growth_factor,
+                newest.layer + 1,
+                newest.timestamp,
+            )
+        };
+        let mut filters = layers.filters.clone();
+        filters.push(Arc::new(filter));
+        Layers { filters, target_fpr }
+    }
+
+    /// Layers with a new generation started
+    fn rotate_layers(&self, layers: &Layers) -> Layers {
+        let layer = layers.filters.last().map_or(1, |f| f.layer + 1);
+        let mut filters = layers.filters.clone();
+        filters.push(Arc::new(AtomicLayer::new(self.initial_hashes, self.partition_size, layer, self.clock.now())));
+        Layers { filters, target_fpr: self.initial_fpr }
+    }
+
+    /// Adds `input` to the current generation, unless that already holds it. Safe to call from any thread
+    pub fn insert_shared<T: Hash>(&self, input: &T) {
+        let key = key_bytes(input);
+        let inserted = self.layers.read(|layers| {
+            let newest = layers.filters.last().unwrap();
+            newest.touched.store(true, Ordering::Relaxed);
+            let hash = self.layer_hash(&key, newest);
+            if newest.contains_hash(hash) {
+                return true;
+            }
+            let fits = newest.fill_ratio() <= 0.5;
+            if fits {
+                newest.insert_hash(hash);
+            }
+            fits
+        });
+        if inserted {
+            return;
+        }
+
+        // another thread may have grown the filter meanwhile
+        self.layers.update(|layers| (layers.filters.last().unwrap().fill_ratio() > 0.5).then(|| self.grow(layers)));
+        self.layers.read(|layers| {
+            let newest = layers.filters.last().unwrap();
+            newest.touched.store(true, Ordering::Relaxed);
+            newest.insert_hash(self.layer_hash(&key, newest));
+        });
+    }
+
+    /// Safe to call from any thread
+    pub fn contains_shared<T: Hash>(&self, input: &T) -> bool {
+        let key = key_bytes(input);
+        self.layers.read(|layers| layers.filters.iter().any(|f| f.contains_hash(self.layer_hash(&key, f))))
+    }
+}
+
+impl<H: Hash128 + Clone + Send + Sync + 'static> MembershipFilter for ConcurrentBloomFilter<H> {
+    fn new(config: &FilterConfig, clock: Clock) -> Self {
+        let first = AtomicLayer::new(hashes_for(config.target_fpr), config.partition_size, 1, clock.now());
+        let layers = Layers { filters: vec![Arc::new(first)], target_fpr: config.target_fpr };
+        Self::from_layers(config, config.hash_seed, clock, layers)
+    }
+
+    fn insert<T: Hash>(&mut self, input: &T) {
+        self.insert_shared(input);
+    }
+
+    fn contains<T: Hash>(&self, input: &T) -> bool {
+        self.contains_shared(input)
+    }
+
+    /// Always false, bits are shared between IDs without counters so nothing can be removed
+    fn remove<T: Hash>(&mut self, _input: &T) -> bool {
+        false
+    }
+
+    fn fpr(&self) -> f64 {
+        self.layers.read(|layers| {
+            1.0 - layers.filters.iter()
+                .map(|f| 1.0 - 0.5f64.powi(f.hashes as i32))
+                .product::<f64>()
+        })
+    }
+
+    fn stats(&self) -> MembershipStats {
+        self.layers.read(|layers| {
+            let total_bits = layers.filters.iter().map(|f| f.size).sum();
+            let set_bits: usize = layers.filters.iter().map(|f| f.set_bits.load(Ordering::Relaxed)).sum();
+            MembershipStats {
+                backend: "concurrent",
+                layer_count: layers.filters.len(),
+                total_bits,
+                fill_ratio: set_bits as f64 / total_bits as f64,
+                layer_fill: layers.filters.iter().map(|f| f.fill_ratio()).collect(),
+                est_fpr: 1.0 - layers.filters.iter().map(|f| 1.0 - 0.5f64.powi(f.hashes as i32)).product::<f64>(),
+            }
+        })
+    }
+
+    fn rotate(&mut self) {
+        self.layers.update(|layers| Some(self.rotate_layers(layers)));
+    }
+
+    fn generation_age(&self) -> Duration {
+        let now = self.clock.now();
+        self.layers.read(|layers| layers.filters.last().map_or(Duration::ZERO, |f| f.age(now)))
+    }
+
+    fn prune(&mut self, max_age: Duration) {
+        let now = self.clock.now();
+        self.layers.update(|layers| {
+            for filter in &layers.filters {
+                if filter.touched.swap(false, Ordering::Relaxed) {
+                    filter.idle_since.store(millis(now), Ordering::Relaxed);
+                }
+            }
+            let filters: Vec<_> = layers.filters.iter().filter(|f| f.idle(now) < max_age).cloned().collect();
+            if filters.len() == layers.filters.len() {
+                return None;
+            }
+            let pruned = Layers { filters, target_fpr: layers.target_fpr };
+            Some(if pruned.filters.is_empty() { self.rotate_layers(&pruned) } else { pruned })
+        });
+    }
+
+    /// Writes the layers and their ages, all integers little-endian: magic, version, hash id, seed, target FPR,
+    /// layer count, then per layer hashes, layer, partition size, generation age and idle time in ms, set bits
+    /// and bit words
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let now = self.clock.now();
+        self.layers.read(|layers| {
+            writer.write_all(STATE_MAGIC)?;
+            writer.write_all(&[STATE_VERSION, H::ID])?;
+            writer.write_all(&self.seed.to_le_bytes())?;
+            writer.write_all(&layers.target_fpr.to_le_bytes())?;
+            writer.write_all(&(layers.filters.len() as u32).to_le_bytes())?;
+            for filter in &layers.filters {
+                writer.write_all(&(filter.hashes as u32).to_le_bytes())?;
+                writer.write_all(&(filter.layer as u32).to_le_bytes())?;
+                writer.write_all(&(filter.partition_size as u64).to_le_bytes())?;
+                writer.write_all(&(filter.age(now).as_millis() as u64).to_le_bytes())?;
+                // a layer written since the last prune isn't idle
+                let idle = if filter.touched.load(Ordering::Relaxed) { Duration::ZERO } else { filter.idle(now) };
+                writer.write_all(&(idle.as_millis() as u64).to_le_bytes())?;
+                for word in &filter.words {
+                    writer.write_all(&word.load(Ordering::Relaxed).to_le_bytes())?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Restores layers written by `write_to` along with their seed, layers added later use `config`
+    fn read_from<R: Read>(reader: &mut R, config: &FilterConfig, clock: Clock) -> io::Result<Self> {
+        read_magic(reader, STATE_MAGIC, STATE_VERSION)?;
+        let hash = read_u8(reader)?;
+        if hash != H::ID {
+            return Err(invalid_state(&format!("written with hash {hash}, expected {}", H::ID)));
+        }
+
+        let seed = read_u32(reader)?;
+        let target_fpr = f64::from_bits(read_u64(reader)?);
+        let now = clock.now();
+
+        let count = read_u32(reader)?;
+        if count == 0 {
+            return Err(invalid_state("no layers"));
+        }
+        let filters = (0..count).map(|_| {
+            let hashes = read_u32(reader)? as usize;
+            let layer = read_u32(reader)? as usize;
+            let partition_size = read_u64(reader)? as usize;
+            let size = layer_bits(hashes, partition_size)?;
+            let carried_age = Duration::from_millis(read_u64(reader)?);
+            let carried_idle = read_u64(reader)? as i64;
+
+            let bytes = read_bytes(reader, size.div_ceil(64) * 8)?;
+            let set_bits = bytes.iter().map(|b| b.count_ones() as usize).sum();
+            Ok(Arc::new(AtomicLayer {
+                words: bytes.chunks_exact(8).map(|w| AtomicU64::new(u64::from_le_bytes(w.try_into().unwrap()))).collect(),
+                set_bits: AtomicUsize::new(set_bits),
+                size,
+                partition_size,
+                carried_age,
+                idle_since: AtomicI64::new(millis(now).saturating_sub(carried_idle)),
+                ..AtomicLayer::new(hashes, 0, layer, now)
+            }))
+        }).collect::<io::Result<_>>()?;
+
+        Ok(Self::from_layers(config, seed, clock, Layers { filters, target_fpr }))
+    }
+
+    fn shared(&self) -> Option<Arc<dyn SharedFilter>> {
+        Some(Arc::new(self.clone()))
+    }
+}
+
+impl<H: Hash128 + Clone + Send + Sync + 'static> SharedFilter for ConcurrentBloomFilter<H> {
+    fn check_trusted(&self, key: &str) -> bool {
+        let trusted = self.contains_shared(&key);
+        if trusted {
+            self.insert_shared(&key);
+        }
+        trusted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_input;
+
+    #[test]
+    fn test_parallel_inserts() {
+        let filter: ConcurrentBloomFilter = ConcurrentBloomFilter::new(&FilterConfig::default(), Clock::new());
+        let inputs: Vec<Vec<String>> = (0..4).map(|seed| gen_input(2048, seed)).collect();
+
+        thread::scope(|scope| {
+            for input in &inputs {
+                let filter = filter.clone();
+                scope.spawn(move || input.iter().for_each(|i| filter.insert_shared(i)));
+            }
+        });
+
+        assert!(inputs.iter().flatten().all(|i| filter.contains_shared(i)));
+        let stats = filter.stats();
+        assert!(stats.layer_count > 1);
+        assert!(stats.layer_fill.iter().all(|f| *f <= 0.6), "layer fill {:?}", stats.layer_fill);
+
+        let false_positives = gen_input(10_000, 100).iter().filter(|i| filter.contains_shared(i)).count();
+        assert!((false_positives as f64) < 10_000.0 * 1.5 * filter.fpr(), "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_prune_while_inserting() {
+        let clock = Clock::new();
+        clock.observe(None, 0.0);
+        let mut filter: ConcurrentBloomFilter = ConcurrentBloomFilter::new(&FilterConfig::default(), clock.clone());
+        let inputs: Vec<Vec<String>> = (0..4).map(|seed| gen_input(4096, seed)).collect();
+
+        thread::scope(|scope| {
+            for input in &inputs {
+                let filter = filter.clone();
+                scope.spawn(move || input.iter().for_each(|i| {
+                    filter.insert_shared(i);
+                    filter.contains_shared(i);
+                }));
+            }
+            // layers are replaced under the inserting threads
+            for ts in 1..200 {
+                clock.observe(None, ts as f64);
+                filter.rotate();
+                filter.prune(Duration::from_secs(5));
+            }
+        });
+
+        // the first prune after the inserts only notes which layers they wrote to
+        for ts in [300.0, 310.0] {
+            clock.observe(None, ts);
+            filter.prune(Duration::from_secs(5));
+        }
+        assert_eq!(1, filter.stats().layer_count);
+        assert!(!filter.contains_shared(&inputs[0][0]));
+    }
+
+    #[test]
+    fn test_retired_under_constant_reads() {
+        let clock = Clock::new();
+        clock.observe(None, 0.0);
+        let mut filter: ConcurrentBloomFilter = ConcurrentBloomFilter::new(&FilterConfig::default(), clock.clone());
+        let input = gen_input(64, 1);
+        let reading = AtomicBool::new(true);
+
+        let most_retired = thread::scope(|scope| {
+            for _ in 0..4 {
+                let (filter, input, reading) = (filter.clone(), &input, &reading);
+                scope.spawn(move || while reading.load(Ordering::Relaxed) {
+                    input.iter().for_each(|i| filter.insert_shared(i));
+                });
+            }
+            // there is always a reader, yet only the snapshots the readers still hold are kept
+            let most_retired = (1..2000).map(|ts| {
+                clock.observe(None, ts as f64);
+                filter.rotate();
+                filter.prune(Duration::from_secs(5));
+                filter.layers.retired.lock().unwrap().len()
+            }).max();
+            reading.store(false, Ordering::Relaxed);
+            most_retired.unwrap()
+        });
+        assert!(most_retired <= 4, "{most_retired} snapshots retired");
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let clock = Clock::new();
+        clock.observe(None, 0.0);
+        let mut filter: ConcurrentBloomFilter = ConcurrentBloomFilter::new(&FilterConfig::default(), clock.clone());
+        let input = gen_input(4096, 1);
+        input.iter().for_each(|i| filter.insert(i));
+        filter.prune(Duration::from_secs(300));
+        clock.observe(None, 60.0);
+
+        let mut state = Vec::new();
+        filter.write_to(&mut state).unwrap();
+
+        let restarted = Clock::new();
+        let mut restored: ConcurrentBloomFilter = ConcurrentBloomFilter::read_from(&mut state.as_slice(), &FilterConfig::default(), restarted.clone()).unwrap();
+        assert_eq!(filter.stats().layer_fill, restored.stats().layer_fill);
+        assert!(input.iter().all(|i| restored.contains(i)));
+
+        // restored layers keep their idle time
+        restarted.observe(None, 0.0);
+        restarted.observe(None, 2.0);
+        restored.prune(Duration::from_secs(61));
+        assert!(!restored.contains(&input[0]));
+
+        // first layer's hash count and partition size, too large to allocate
+        let read = |state: &[u8]| ConcurrentBloomFilter::<Murmur3>::read_from(&mut &state[..], &FilterConfig::default(), Clock::new());
+        state[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        state[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&state).is_err());
+        state[24..28].copy_from_slice(&1u32.to_le_bytes());
+        state[32..40].copy_from_slice(&(1u64 << 32).to_le_bytes());
+        assert_eq!(io::ErrorKind::UnexpectedEof, read(&state).err().unwrap().kind());
+    }
+}
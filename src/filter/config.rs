@@ -0,0 +1,94 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Membership filter holding promoted IDs
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+#[value(rename_all = "lowercase")]
+pub enum FilterBackend {
+    /// Scalable Bloom filter, IDs are only forgotten when their layer ages out
+    #[default]
+    Bloom,
+    /// Scalable Bloom filter with 4-bit counters per slot, so single IDs can be removed
+    Counting,
+    /// Layered cuckoo filter of 16-bit fingerprints, removes IDs and uses less memory at low FPRs
+    Cuckoo,
+    /// Scalable Bloom filter of atomic words, checked by the ingest threads in parallel
+    Concurrent,
+}
+
+/// Tuning of the promotion filter, defaults suit a single receiver's traffic
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub backend: FilterBackend,
+    /// Sightings before an ID is promoted into the Bloom filter
+    pub threshold: u8,
+    /// Seconds in which the sightings must fall, older pending sightings expire
+    pub promotion_window: f64,
+    /// False positive rate of the first layer
+    pub target_fpr: f64,
+    /// Bits per hash partition
+    pub partition_size: usize,
+    /// Size multiplier of layers added once the first fills up
+    pub growth_factor: usize,
+    /// Factor applied to the target FPR of every added layer
+    pub tightening_ratio: f64,
+    /// Seed of the filter hash, processes sharing filters must agree on it
+    pub hash_seed: u32,
+    /// Generations trusted IDs rotate through within the filter max age. More generations evict silent
+    /// aircraft closer to the max age, at the cost of more layers
+    pub generations: u32,
+    /// Never inserted keys probed at every prune to measure the observed FPR, 0 disables measuring
+    pub fpr_probes: usize,
+    /// Fingerprints held per cuckoo filter layer, rounded up to a power of two buckets
+    pub cuckoo_capacity: usize,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            backend: FilterBackend::Bloom,
+            threshold: 3,
+            promotion_window: 30.0,
+            target_fpr: 0.001,
+            partition_size: 2048,
+            growth_factor: 2,
+            tightening_ratio: 0.8,
+            hash_seed: 0,
+            generations: 4,
+            fpr_probes: 0,
+            cuckoo_capacity: 65_536,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            return Err("filter threshold must be at least 1".to_string());
+        }
+        if !(self.promotion_window.is_finite() && self.promotion_window > 0.0) {
+            return Err(format!("promotion window {} must be positive", self.promotion_window));
+        }
+        if !(self.target_fpr > 0.0 && self.target_fpr < 1.0) {
+            return Err(format!("target FPR {} must be between 0 and 1", self.target_fpr));
+        }
+        if self.partition_size == 0 {
+            return Err("partition size must be positive".to_string());
+        }
+        if self.growth_factor == 0 {
+            return Err("growth factor must be at least 1".to_string());
+        }
+        if !(self.tightening_ratio > 0.0 && self.tightening_ratio <= 1.0) {
+            return Err(format!("tightening ratio {} must be in (0, 1]", self.tightening_ratio));
+        }
+        if self.generations == 0 {
+            return Err("filter generations must be at least 1".to_string());
+        }
+        if self.cuckoo_capacity == 0 {
+            return Err("cuckoo capacity must be positive".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,368 @@
+use crate::clock::Clock;
+use crate::filter::config::FilterConfig;
+use crate::filter::hash::{key_bytes, Hash128, Murmur3};
+use crate::filter::membership::{invalid_state, layer_bits, read_bytes, read_magic, read_u32, read_u64, read_u8, MembershipFilter, MembershipStats};
+use std::hash::Hash;
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+const STATE_MAGIC: &[u8; 6] = b"ACTCKF";
+const STATE_VERSION: u8 = 2;
+const BUCKET_SIZE: usize = 4;
+const FINGERPRINT_BITS: i32 = 16;
+/// Relocations tried before an insert gives up on a layer
+const MAX_KICKS: usize = 500;
+/// Load at which a layer counts as full and a new one is started
+const MAX_LOAD: f64 = 0.95;
+/// Fingerprint marking an empty slot
+const EMPTY: u16 = 0;
+
+pub struct CuckooLayer {
+    buckets: Vec<[u16; BUCKET_SIZE]>,
+    len: usize,
+    /// Start of the layer's generation, as read from the owning filter's clock
+    timestamp: Duration,
+    /// Age the layer already had when it was restored from a saved state
+    carried_age: Duration,
+    /// Last time a fingerprint was inserted, or refreshed while already held
+    written: Duration,
+    /// Idle time the layer already had when it was restored
+    carried_idle: Duration,
+}
+
+impl CuckooLayer {
+    fn new(buckets: usize, timestamp: Duration) -> Self {
+        Self {
+            buckets: vec![[EMPTY; BUCKET_SIZE]; buckets],
+            len: 0,
+            timestamp,
+            carried_age: Duration::ZERO,
+            written: timestamp,
+            carried_idle: Duration::ZERO,
+        }
+    }
+
+    fn age(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.timestamp) + self.carried_age
+    }
+
+    fn idle(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.written) + self.carried_idle
+    }
+
+    fn touch(&mut self, now: Duration) {
+        self.written = now;
+        self.carried_idle = Duration::ZERO;
+    }
+
+    fn load(&self) -> f64 {
+        self.len as f64 / (self.buckets.len() * BUCKET_SIZE) as f64
+    }
+
+    /// The other bucket a fingerprint may live in, applying it twice returns the original bucket
+    fn alt_index(&self, idx: usize, fingerprint: u16) -> usize {
+        (idx ^ (fingerprint as usize).wrapping_mul(0x5bd1_e995)) & (self.buckets.len() - 1)
+    }
+
+    fn put(&mut self, idx: usize, fingerprint: u16) -> bool {
+        match self.buckets[idx].iter_mut().find(|slot| **slot == EMPTY) {
+            Some(slot) => {
+                *slot = fingerprint;
+                self.len += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, idx: usize, fingerprint: u16) -> bool {
+        self.buckets[idx].contains(&fingerprint) || self.buckets[self.alt_index(idx, fingerprint)].contains(&fingerprint)
+    }
+
+    /// Places the fingerprint, relocating others when both buckets are full. Returns the bucket and
+    /// fingerprint left without a slot when the layer is too full
+    fn insert(&mut self, idx: usize, fingerprint: u16, random: &mut impl FnMut() -> usize) -> Option<(usize, u16)> {
+        let alt = self.alt_index(idx, fingerprint);
+        if self.put(idx, fingerprint) || self.put(alt, fingerprint) {
+            return None;
+        }
+
+        let mut idx = if random() & 1 == 0 { idx } else { alt };
+        let mut fingerprint = fingerprint;
+        for _ in 0..MAX_KICKS {
+            std::mem::swap(&mut fingerprint, &mut self.buckets[idx][random() % BUCKET_SIZE]);
+            idx = self.alt_index(idx, fingerprint);
+            if self.put(idx, fingerprint) {
+                return None;
+            }
+        }
+        Some((idx, fingerprint))
+    }
+
+    fn remove(&mut self, idx: usize, fingerprint: u16) -> bool {
+        for idx in [idx, self.alt_index(idx, fingerprint)] {
+            if let Some(slot) = self.buckets[idx].iter_mut().find(|slot| **slot == fingerprint) {
+                *slot = EMPTY;
+                self.len -= 1;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Cuckoo filter of 16-bit fingerprints in buckets of four, supporting deletion. Layers share their geometry
+/// and seed, so a fingerprint evicted from a full layer moves into the next one unchanged
+pub struct CuckooFilter<H: Hash128 = Murmur3> {
+    pub layers: Vec<CuckooLayer>,
+    hasher: H,
+    seed: u32,
+    buckets: usize,
+    /// xorshift state choosing relocation victims, seeded so filters are reproducible
+    kick_state: u64,
+    clock: Clock,
+}
+
+impl<H: Hash128> CuckooFilter<H> {
+    /// Bucket and fingerprint of `input`
+    fn locate<T: Hash>(&self, input: &T) -> (usize, u16) {
+        let (h1, h2) = self.hasher.hash128(&key_bytes(input), self.seed);
+        let fingerprint = (h2 as u16).max(1);
+        (h1 as usize & (self.buckets - 1), fingerprint)
+    }
+
+    fn new_layer(&self) -> CuckooLayer {
+        CuckooLayer::new(self.buckets, self.clock.now())
+    }
+
+    /// Layer taking the fingerprints that no longer fit the newest, it belongs to the same generation
+    fn overflow_layer(&self) -> CuckooLayer {
+        let newest = self.layers.last().unwrap();
+        CuckooLayer {
+            timestamp: newest.timestamp,
+            carried_age: newest.carried_age,
+            ..self.new_layer()
+        }
+    }
+}
+
+impl<H: Hash128> MembershipFilter for CuckooFilter<H> {
+    fn new(config: &FilterConfig, clock: Clock) -> Self {
+        let buckets = config.cuckoo_capacity.div_ceil(BUCKET_SIZE).next_power_of_two();
+        Self {
+            layers: vec![CuckooLayer::new(buckets, clock.now())],
+            hasher: H::default(),
+            seed: config.hash_seed,
+            buckets,
+            kick_state: 0x9e37_79b9_7f4a_7c15 ^ config.hash_seed as u64,
+            clock,
+        }
+    }
+
+    fn insert<T: Hash>(&mut self, input: &T) {
+        let (idx, fingerprint) = self.locate(input);
+        let newest = self.layers.last_mut().unwrap();
+        newest.touch(self.clock.now());
+        if newest.contains(idx, fingerprint) {
+            return;
+        }
+        if newest.load() >= MAX_LOAD {
+            self.layers.push(self.overflow_layer());
+        }
+
+        let state = &mut self.kick_state;
+        let mut random = || {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            *state as usize
+        };
+        let homeless = self.layers.last_mut().unwrap().insert(idx, fingerprint, &mut random);
+        if let Some((idx, fingerprint)) = homeless {
+            let mut layer = self.overflow_layer();
+            layer.put(idx, fingerprint);
+            self.layers.push(layer);
+        }
+    }
+
+    fn contains<T: Hash>(&self, input: &T) -> bool {
+        let (idx, fingerprint) = self.locate(input);
+        self.layers.iter().any(|layer| layer.contains(idx, fingerprint))
+    }
+
+    fn remove<T: Hash>(&mut self, input: &T) -> bool {
+        let (idx, fingerprint) = self.locate(input);
+        // a refreshed ID is held once per generation
+        self.layers.iter_mut().map(|layer| layer.remove(idx, fingerprint)).fold(false, |removed, r| removed | r)
+    }
+
+    fn fpr(&self) -> f64 {
+        1.0 - self.layers.iter()
+            .map(|layer| 1.0 - (2.0 * BUCKET_SIZE as f64 * layer.load() / 2f64.powi(FINGERPRINT_BITS)).min(1.0))
+            .product::<f64>()
+    }
+
+    fn stats(&self) -> MembershipStats {
+        MembershipStats {
+            backend: "cuckoo",
+            layer_count: self.layers.len(),
+            total_bits: self.layers.len() * self.buckets * BUCKET_SIZE * FINGERPRINT_BITS as usize,
+            fill_ratio: self.layers.iter().map(CuckooLayer::load).sum::<f64>() / self.layers.len() as f64,
+            layer_fill: self.layers.iter().map(CuckooLayer::load).collect(),
+            est_fpr: self.fpr(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.layers.push(self.new_layer());
+    }
+
+    fn generation_age(&self) -> Duration {
+        self.layers.last().map_or(Duration::ZERO, |layer| layer.age(self.clock.now()))
+    }
+
+    fn prune(&mut self, max_age: Duration) {
+        let now = self.clock.now();
+        self.layers.retain(|layer| layer.idle(now) < max_age);
+        if self.layers.is_empty() {
+            self.rotate();
+        }
+    }
+
+    /// Writes the layers and their ages, all integers little-endian: magic, version, hash id, seed,
+    /// bucket count, layer count, then per layer generation age and idle time in ms, and fingerprints
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let now = self.clock.now();
+        writer.write_all(STATE_MAGIC)?;
+        writer.write_all(&[STATE_VERSION, H::ID])?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&(self.buckets as u64).to_le_bytes())?;
+        writer.write_all(&(self.layers.len() as u32).to_le_bytes())?;
+        for layer in &self.layers {
+            writer.write_all(&(layer.age(now).as_millis() as u64).to_le_bytes())?;
+            writer.write_all(&(layer.idle(now).as_millis() as u64).to_le_bytes())?;
+            for fingerprint in layer.buckets.iter().flatten() {
+                writer.write_all(&fingerprint.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores layers written by `write_to` along with their seed and geometry
+    fn read_from<R: Read>(reader: &mut R, config: &FilterConfig, clock: Clock) -> io::Result<Self> {
+        read_magic(reader, STATE_MAGIC, STATE_VERSION)?;
+        let hash = read_u8(reader)?;
+        if hash != H::ID {
+            return Err(invalid_state(&format!("written with hash {hash}, expected {}", H::ID)));
+        }
+
+        let seed = read_u32(reader)?;
+        let buckets = read_u64(reader)? as usize;
+        if !buckets.is_power_of_two() {
+            return Err(invalid_state("bucket count is not a power of two"));
+        }
+        let slots = layer_bits(buckets, BUCKET_SIZE * FINGERPRINT_BITS as usize)? / FINGERPRINT_BITS as usize;
+        let now = clock.now();
+
+        let count = read_u32(reader)?;
+        if count == 0 {
+            return Err(invalid_state("no layers"));
+        }
+        let layers = (0..count).map(|_| {
+            let carried_age = Duration::from_millis(read_u64(reader)?);
+            let carried_idle = Duration::from_millis(read_u64(reader)?);
+            let buf = read_bytes(reader, slots * 2)?;
+            let buckets: Vec<[u16; BUCKET_SIZE]> = buf.chunks_exact(BUCKET_SIZE * 2)
+                .map(|bucket| std::array::from_fn(|i| u16::from_le_bytes([bucket[i * 2], bucket[i * 2 + 1]])))
+                .collect();
+            let len = buckets.iter().flatten().filter(|slot| **slot != EMPTY).count();
+            Ok(CuckooLayer { buckets, len, timestamp: now, carried_age, written: now, carried_idle })
+        }).collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            layers,
+            hasher: H::default(),
+            seed,
+            buckets,
+            kick_state: 0x9e37_79b9_7f4a_7c15 ^ config.hash_seed as u64,
+            clock,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::gen_input;
+
+    fn small_config() -> FilterConfig {
+        FilterConfig { cuckoo_capacity: 1024, ..FilterConfig::default() }
+    }
+
+    #[test]
+    fn test_positive_and_growth() {
+        let mut cf: CuckooFilter = CuckooFilter::new(&small_config(), Clock::new());
+        let input = gen_input(4096, 1);
+        input.iter().for_each(|i| cf.insert(i));
+
+        for i in &input {
+            assert!(cf.contains(i), "input {i}");
+        }
+        assert!(cf.layers.len() >= 4);
+        assert_eq!(4096, cf.layers.iter().map(|l| l.len).sum::<usize>());
+    }
+
+    #[test]
+    fn test_negative() {
+        let mut cf: CuckooFilter = CuckooFilter::new(&small_config(), Clock::new());
+        gen_input(1024, 1).iter().for_each(|i| cf.insert(i));
+
+        let false_positives = gen_input(10_000, 2).iter().filter(|i| cf.contains(i)).count();
+        assert!(false_positives < 20, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cf: CuckooFilter = CuckooFilter::new(&small_config(), Clock::new());
+        let input = gen_input(900, 1);
+        input.iter().for_each(|i| cf.insert(i));
+
+        assert!(cf.remove(&input[0]));
+        assert!(!cf.contains(&input[0]));
+        assert!(!cf.remove(&input[0]));
+        assert!(input[1..].iter().all(|i| cf.contains(i)));
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let clock = Clock::new();
+        clock.observe(None, 0.0);
+        let mut cf: CuckooFilter = CuckooFilter::new(&small_config(), clock.clone());
+        let input = gen_input(2048, 1);
+        input.iter().for_each(|i| cf.insert(i));
+        clock.observe(None, 60.0);
+
+        let mut state = Vec::new();
+        cf.write_to(&mut state).unwrap();
+
+        let restarted = Clock::new();
+        let mut restored: CuckooFilter = CuckooFilter::read_from(&mut state.as_slice(), &FilterConfig::default(), restarted.clone()).unwrap();
+        assert_eq!(cf.layers.len(), restored.layers.len());
+        assert!(input.iter().all(|i| restored.contains(i)));
+
+        // restored layers keep their age
+        restarted.observe(None, 0.0);
+        restarted.observe(None, 2.0);
+        restored.prune(Duration::from_secs(61));
+        assert!(!restored.contains(&input[0]));
+
+        assert!(CuckooFilter::<Murmur3>::read_from(&mut &state[..20], &FilterConfig::default(), Clock::new()).is_err());
+
+        // bucket counts too large to allocate fail without trying to
+        for buckets in [1u64 << 62, 1 << 28] {
+            state[12..20].copy_from_slice(&buckets.to_le_bytes());
+            assert!(CuckooFilter::<Murmur3>::read_from(&mut state.as_slice(), &FilterConfig::default(), Clock::new()).is_err());
+        }
+    }
+}
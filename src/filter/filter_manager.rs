@@ -1,72 +1,167 @@
+use crate::clock::Clock;
 use crate::filter::bloom_filter::ScalableBloomFilter;
-use std::collections::HashMap;
+use crate::filter::config::FilterConfig;
+use crate::filter::membership::{MembershipFilter, SharedFilter};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
+/// Prefix of the keys probing the observed FPR, no callsign or ICAO address starts with NUL
+const PROBE_PREFIX: &str = "\0probe:";
+
 pub struct FilterStats {
+    pub backend: &'static str,
     pub layer_count: usize,
     pub total_bits: usize,
     pub fill_ratio: f64,
+    pub layer_fill: Vec<f64>,
     pub est_fpr: f64,
+    /// Share of never inserted keys the filter matched at the last prune, when measuring
+    pub observed_fpr: Option<f64>,
     pub pending: usize,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum FilterResult {
     Pending,
     Promoted,
     Trusted,
 }
 
-pub struct FilterManager<T: Hash> {
-    sbf: ScalableBloomFilter,
-    pub pending: HashMap<T, u8>,
+/// Sightings of an ID not yet promoted, only those inside the promotion window are kept
+pub struct Pending {
+    sightings: VecDeque<Duration>,
+}
+
+impl Pending {
+    pub fn count(&self) -> usize {
+        self.sightings.len()
+    }
+
+    pub fn last_seen(&self) -> Duration {
+        self.sightings.back().copied().unwrap_or_default()
+    }
+}
+
+pub struct FilterManager<T: Hash, F: MembershipFilter = ScalableBloomFilter> {
+    filter: F,
+    pub pending: HashMap<T, Pending>,
     threshold: u8,
+    window: Duration,
+    generations: u32,
+    fpr_probes: usize,
+    /// Draws the probe keys, seeded from the hash seed so measurements are reproducible
+    probe_rng: StdRng,
+    observed_fpr: Option<f64>,
+    clock: Clock,
 }
 
-impl<T: Clone + Eq + Hash> FilterManager<T> {
-    pub fn new() -> Self {
+impl<T: Clone + Eq + Hash, F: MembershipFilter> FilterManager<T, F> {
+    pub fn new(config: &FilterConfig, clock: Clock) -> Self {
         Self {
-            sbf: ScalableBloomFilter::new(),
+            filter: F::new(config, clock.clone()),
             pending: HashMap::new(),
-            threshold: 3,
+            threshold: config.threshold,
+            window: Duration::from_secs_f64(config.promotion_window),
+            generations: config.generations,
+            fpr_probes: config.fpr_probes,
+            probe_rng: StdRng::seed_from_u64(config.hash_seed as u64),
+            observed_fpr: None,
+            clock,
         }
     }
 
+    /// Restores the trusted IDs saved at `path`, pending sightings are not persisted
+    pub fn load(path: &Path, config: &FilterConfig, clock: Clock) -> io::Result<Self> {
+        Ok(Self {
+            filter: F::load(path, config, clock.clone())?,
+            ..Self::new(config, clock)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.filter.save(path)
+    }
+
+    /// Handle to the trusted IDs for ingest threads, when the backend can be shared
+    pub fn shared(&self) -> Option<Arc<dyn SharedFilter>> {
+        self.filter.shared()
+    }
+
     pub fn fpr(&self) -> f64 {
-        self.sbf.fpr()
+        self.filter.fpr()
     }
 
     pub fn insert(&mut self, input: &T) -> FilterResult {
-        if self.sbf.contains(input) {
+        if self.filter.contains(input) {
+            // keeps the ID in the current generation for as long as it transmits
+            self.filter.insert(input);
             return FilterResult::Trusted;
         }
 
-        let count = self.pending.entry(input.clone()).or_insert(0);
-        *count += 1;
+        let now = self.clock.now();
+        let pending = self.pending.entry(input.clone()).or_insert_with(|| Pending { sightings: VecDeque::new() });
+        while pending.sightings.front().is_some_and(|seen| now.saturating_sub(*seen) > self.window) {
+            pending.sightings.pop_front();
+        }
+        pending.sightings.push_back(now);
 
-        if *count >= self.threshold {
+        if pending.count() >= self.threshold as usize {
             self.pending.remove(input);
-            self.sbf.insert(input);
+            self.filter.insert(input);
             return FilterResult::Promoted;
         }
 
         FilterResult::Pending
     }
 
+    /// Sends `input` back to pending, the Bloom backend can't forget trusted IDs.
+    /// Returns whether it was trusted before
+    pub fn remove(&mut self, input: &T) -> bool {
+        self.pending.remove(input);
+        self.filter.remove(input)
+    }
+
+    /// Starts a new filter generation every `max_age / generations`, and drops filter layers idle for `max_age`.
+    /// Trusted IDs seen within `max_age` are kept, silent ones expire after at most one more generation.
+    /// Pending IDs not seen within the promotion window are dropped too, and the FPR measured if enabled
     pub fn prune(&mut self, max_age: Duration) {
-        self.sbf.prune(max_age);
-        self.pending.clear();
+        if self.filter.generation_age() >= max_age / self.generations {
+            self.filter.rotate();
+        }
+        self.filter.prune(max_age);
+        let now = self.clock.now();
+        self.pending.retain(|_, pending| now.saturating_sub(pending.last_seen()) <= self.window);
+
+        if self.fpr_probes > 0 {
+            self.observed_fpr = Some(self.measure_fpr(self.fpr_probes));
+        }
+    }
+
+    /// Share of `probes` random never inserted keys the filter matches
+    pub fn measure_fpr(&mut self, probes: usize) -> f64 {
+        let hits = (0..probes)
+            .filter(|_| self.filter.contains(&format!("{PROBE_PREFIX}{:016x}", self.probe_rng.random::<u64>())))
+            .count();
+        hits as f64 / probes as f64
     }
 
     pub fn stats(&self) -> FilterStats {
-        let total_bits = self.sbf.filters.iter().map(|l| l.size * 8).sum();
-        let set_bits = self.sbf.filters.iter().map(|l| l.bits.iter().map(|v| v.count_ones()).sum::<u32>()).sum::<u32>() as usize;
+        let stats = self.filter.stats();
         FilterStats {
-            layer_count: self.sbf.filters.len(),
-            total_bits,
-            fill_ratio: set_bits as f64 / total_bits as f64,
-            est_fpr: self.fpr(),
+            backend: stats.backend,
+            layer_count: stats.layer_count,
+            total_bits: stats.total_bits,
+            fill_ratio: stats.fill_ratio,
+            layer_fill: stats.layer_fill,
+            est_fpr: stats.est_fpr,
+            observed_fpr: self.observed_fpr,
             pending: self.pending.len(),
         }
     }
@@ -75,37 +170,48 @@ impl<T: Clone + Eq + Hash> FilterManager<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filter::config::FilterBackend;
+    use crate::filter::cuckoo_filter::CuckooFilter;
 
     #[test]
     fn test_pending() {
-        let mut fm = FilterManager::<&str>::new();
+        let mut fm = FilterManager::<&str>::new(&FilterConfig::default(), Clock::new());
         let plane = "ALPHA1";
 
         // 1st check
         assert_eq!(FilterResult::Pending, fm.insert(&plane));
         assert_eq!(1, fm.pending.len());
-        assert_eq!(Some(&1u8), fm.pending.get(&plane));
-        assert!(fm.sbf.filters.iter().all(|f| f.bits.iter().all(|x| x.count_ones() == 0)));
+        assert_eq!(Some(1), fm.pending.get(&plane).map(Pending::count));
+        assert!(fm.filter.filters.iter().all(|f| f.bits.iter().all(|x| x.count_ones() == 0)));
 
         // 2nd check
         assert_eq!(FilterResult::Pending, fm.insert(&plane));
         assert_eq!(1, fm.pending.len());
-        assert_eq!(Some(&2u8), fm.pending.get(&plane));
-        assert!(fm.sbf.filters.iter().all(|f| f.bits.iter().all(|x| x.count_ones() == 0)));
+        assert_eq!(Some(2), fm.pending.get(&plane).map(Pending::count));
+        assert!(fm.filter.filters.iter().all(|f| f.bits.iter().all(|x| x.count_ones() == 0)));
 
         // 3rd check
         assert_eq!(FilterResult::Promoted, fm.insert(&plane));
         assert!(fm.pending.is_empty());
-        assert!(fm.sbf.filters.iter().all(|f| f.bits.iter().any(|x| x.count_ones() > 0)));
+        assert!(fm.filter.filters.iter().all(|f| f.bits.iter().any(|x| x.count_ones() > 0)));
 
         // 4th check (bf)
         assert_eq!(FilterResult::Trusted, fm.insert(&plane));
         assert!(fm.pending.is_empty());
-        assert!(fm.sbf.filters.iter().all(|f| f.bits.iter().any(|x| x.count_ones() > 0)));
+        assert!(fm.filter.filters.iter().all(|f| f.bits.iter().any(|x| x.count_ones() > 0)));
+    }
+
+    #[test]
+    fn test_threshold() {
+        let config = FilterConfig { threshold: 1, ..FilterConfig::default() };
+        let mut fm = FilterManager::<&str>::new(&config, Clock::new());
+
+        assert_eq!(FilterResult::Promoted, fm.insert(&"ALPHA1"));
+        assert_eq!(FilterResult::Trusted, fm.insert(&"ALPHA1"));
     }
     #[test]
     fn test_prune() {
-        let mut fm = FilterManager::<&str>::new();
+        let mut fm = FilterManager::<&str>::new(&FilterConfig::default(), Clock::new());
         let plane = "ALPHA1";
 
         fm.insert(&plane);
@@ -117,4 +223,156 @@ mod tests {
 
         assert_eq!(FilterResult::Trusted, fm.insert(&plane));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_prune_on_event_time() {
+        let clock = Clock::new();
+        let mut fm = FilterManager::<&str>::new(&FilterConfig::default(), clock.clone());
+        let plane = "ALPHA1";
+
+        clock.observe(None, 1000.0);
+        fm.prune(Duration::from_secs(10));
+        for _ in 0..3 {
+            fm.insert(&plane);
+        }
+        assert_eq!(FilterResult::Trusted, fm.insert(&plane));
+
+        clock.observe(None, 1009.0);
+        fm.prune(Duration::from_secs(10));
+        assert!(fm.filter.contains(&plane));
+
+        // a replayed capture ages the filter without waiting in real time
+        clock.observe(None, 1011.0);
+        fm.prune(Duration::from_secs(10));
+        assert_eq!(FilterResult::Pending, fm.insert(&plane));
+    }
+
+    #[test]
+    fn test_measure_fpr() {
+        let config = FilterConfig { threshold: 1, target_fpr: 0.01, fpr_probes: 1000, ..FilterConfig::default() };
+        let mut fm = FilterManager::<String>::new(&config, Clock::new());
+        assert_eq!(0.0, fm.measure_fpr(1000));
+        assert_eq!(None, fm.stats().observed_fpr);
+
+        // probes are drawn from the hash seed, so equal filters measure the same
+        let mut same_seed = FilterManager::<String>::new(&config, Clock::new());
+        same_seed.measure_fpr(1000);
+        for i in 0..5000 {
+            fm.insert(&format!("ID{i}"));
+            same_seed.insert(&format!("ID{i}"));
+        }
+        let observed = fm.measure_fpr(100_000);
+        // grown layers must hold the estimate too
+        assert!(observed > 0.0 && observed < 1.5 * fm.fpr(), "observed FPR {observed}, estimated {}", fm.fpr());
+        assert_eq!(observed, same_seed.measure_fpr(100_000));
+
+        fm.prune(Duration::from_secs(300));
+        let stats = fm.stats();
+        assert!(stats.observed_fpr.is_some());
+        assert_eq!(stats.layer_count, stats.layer_fill.len());
+        assert!(stats.layer_fill.iter().all(|f| *f > 0.0 && *f <= 0.6));
+    }
+
+    #[test]
+    fn test_generations() {
+        let clock = Clock::new();
+        let mut fm = FilterManager::<&str>::new(&FilterConfig::default(), clock.clone());
+        let max_age = Duration::from_secs(300);
+
+        clock.observe(None, 0.0);
+        for _ in 0..3 {
+            fm.insert(&"ACTIVE");
+            fm.insert(&"SILENT");
+        }
+
+        // an aircraft that keeps transmitting outlives the generation it was promoted in
+        for ts in (5..=1200).step_by(5) {
+            clock.observe(None, ts as f64);
+            fm.prune(max_age);
+            assert_eq!(FilterResult::Trusted, fm.insert(&"ACTIVE"), "at {ts}s");
+            if ts == 295 {
+                assert!(fm.filter.contains(&"SILENT"));
+            }
+            if ts == 300 + 75 {
+                assert!(!fm.filter.contains(&"SILENT"));
+            }
+        }
+        assert!(fm.stats().layer_count <= 6);
+    }
+
+    #[test]
+    fn test_remove() {
+        let config = FilterConfig { backend: FilterBackend::Counting, ..FilterConfig::default() };
+        let mut fm = FilterManager::<&str>::new(&config, Clock::new());
+        for _ in 0..3 {
+            fm.insert(&"ALPHA1");
+        }
+        fm.insert(&"BRAVO2");
+
+        assert!(fm.remove(&"ALPHA1"));
+        assert!(!fm.remove(&"BRAVO2"));
+        assert!(fm.pending.is_empty());
+        assert_eq!(FilterResult::Pending, fm.insert(&"ALPHA1"));
+
+        // the plain Bloom filter keeps trusting removed IDs
+        let mut fm = FilterManager::<&str>::new(&FilterConfig::default(), Clock::new());
+        for _ in 0..3 {
+            fm.insert(&"ALPHA1");
+        }
+        assert!(!fm.remove(&"ALPHA1"));
+        assert_eq!(FilterResult::Trusted, fm.insert(&"ALPHA1"));
+
+        let mut fm = FilterManager::<&str, CuckooFilter>::new(&FilterConfig::default(), Clock::new());
+        for _ in 0..3 {
+            fm.insert(&"ALPHA1");
+        }
+        assert_eq!("cuckoo", fm.stats().backend);
+        assert!(fm.remove(&"ALPHA1"));
+        assert_eq!(FilterResult::Pending, fm.insert(&"ALPHA1"));
+    }
+
+    #[test]
+    fn test_state_survives_restart() {
+        let path = std::env::temp_dir().join(format!("actsim-{}-filter.state", std::process::id()));
+        let mut fm = FilterManager::<&str>::load(&path, &FilterConfig::default(), Clock::new()).unwrap();
+        for _ in 0..3 {
+            fm.insert(&"ALPHA1");
+        }
+        fm.save(&path).unwrap();
+
+        let mut restarted = FilterManager::<&str>::load(&path, &FilterConfig::default(), Clock::new()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(FilterResult::Trusted, restarted.insert(&"ALPHA1"));
+        assert_eq!(FilterResult::Pending, restarted.insert(&"BRAVO2"));
+    }
+
+    #[test]
+    fn test_promotion_window() {
+        let clock = Clock::new();
+        let config = FilterConfig { promotion_window: 10.0, ..FilterConfig::default() };
+        let mut fm = FilterManager::<&str>::new(&config, clock.clone());
+
+        // sporadic sightings further apart than the window never promote
+        for ts in [0.0, 8.0, 16.0, 24.0] {
+            clock.observe(None, ts);
+            assert_eq!(FilterResult::Pending, fm.insert(&"NOISE"));
+        }
+        assert_eq!(2, fm.pending[&"NOISE"].count());
+
+        // pending sightings survive a prune while inside the window
+        clock.observe(None, 30.0);
+        fm.insert(&"ALPHA1");
+        fm.insert(&"ALPHA1");
+        fm.prune(Duration::from_secs(300));
+        assert_eq!(Some(2), fm.pending.get(&"ALPHA1").map(Pending::count));
+        assert_eq!(FilterResult::Promoted, fm.insert(&"ALPHA1"));
+
+        // and expire individually once stale
+        clock.observe(None, 35.0);
+        fm.insert(&"BRAVO2");
+        clock.observe(None, 41.0);
+        fm.prune(Duration::from_secs(300));
+        assert!(!fm.pending.contains_key(&"NOISE"));
+        assert!(fm.pending.contains_key(&"BRAVO2"));
+    }
+}
@@ -0,0 +1,141 @@
+use std::hash::{Hash, Hasher};
+
+/// 128-bit hash of a byte string, its halves feed Kirsch–Mitzenmacher double hashing so a key is hashed once
+/// per layer however many bit positions it sets. Output must be fixed across builds and platforms, filters
+/// are persisted and shared between processes
+pub trait Hash128: Default {
+    /// Identifies the hash in persisted filter states
+    const ID: u8;
+
+    fn hash128(&self, data: &[u8], seed: u32) -> (u64, u64);
+}
+
+/// MurmurHash3 x64 128-bit
+#[derive(Clone, Copy, Default)]
+pub struct Murmur3;
+
+const C1: u64 = 0x87c3_7b91_1142_53d5;
+const C2: u64 = 0x4cf5_ad43_2745_937f;
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^ (k >> 33)
+}
+
+fn mix_k1(k1: u64) -> u64 {
+    k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2)
+}
+
+fn mix_k2(k2: u64) -> u64 {
+    k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1)
+}
+
+impl Hash128 for Murmur3 {
+    const ID: u8 = 1;
+
+    fn hash128(&self, data: &[u8], seed: u32) -> (u64, u64) {
+        let mut h1 = seed as u64;
+        let mut h2 = seed as u64;
+
+        let mut blocks = data.chunks_exact(16);
+        for block in &mut blocks {
+            let k1 = u64::from_le_bytes(block[..8].try_into().unwrap());
+            let k2 = u64::from_le_bytes(block[8..].try_into().unwrap());
+
+            h1 ^= mix_k1(k1);
+            h1 = h1.rotate_left(27).wrapping_add(h2).wrapping_mul(5).wrapping_add(0x52dc_e729);
+            h2 ^= mix_k2(k2);
+            h2 = h2.rotate_left(31).wrapping_add(h1).wrapping_mul(5).wrapping_add(0x3849_5ab5);
+        }
+
+        let tail = blocks.remainder();
+        let mut k1 = 0u64;
+        let mut k2 = 0u64;
+        for (i, byte) in tail.iter().enumerate() {
+            if i < 8 {
+                k1 |= (*byte as u64) << (8 * i);
+            } else {
+                k2 |= (*byte as u64) << (8 * (i - 8));
+            }
+        }
+        if tail.len() > 8 {
+            h2 ^= mix_k2(k2);
+        }
+        if !tail.is_empty() {
+            h1 ^= mix_k1(k1);
+        }
+
+        h1 ^= data.len() as u64;
+        h2 ^= data.len() as u64;
+        h1 = h1.wrapping_add(h2);
+        h2 = h2.wrapping_add(h1);
+        h1 = fmix64(h1);
+        h2 = fmix64(h2);
+        h1 = h1.wrapping_add(h2);
+        h2 = h2.wrapping_add(h1);
+        (h1, h2)
+    }
+}
+
+/// Collects the bytes a `Hash` impl feeds, integers little-endian and `usize` as 64 bits so keys are
+/// the same on every platform
+struct KeyWriter {
+    bytes: Vec<u8>,
+}
+
+impl Hasher for KeyWriter {
+    fn finish(&self) -> u64 {
+        Murmur3.hash128(&self.bytes, 0).0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// Bytes identifying `input`, hashed once per filter layer
+pub fn key_bytes<T: Hash>(input: &T) -> Vec<u8> {
+    let mut writer = KeyWriter { bytes: Vec::with_capacity(16) };
+    input.hash(&mut writer);
+    writer.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur3_vectors() {
+        assert_eq!((0, 0), Murmur3.hash128(b"", 0));
+        assert_eq!(
+            (0xe34b_bc7b_bc07_1b6c, 0x7a43_3ca9_c49a_9347),
+            Murmur3.hash128(b"The quick brown fox jumps over the lazy dog", 0)
+        );
+        assert_ne!(Murmur3.hash128(b"ABC123", 0), Murmur3.hash128(b"ABC123", 1));
+    }
+
+    #[test]
+    fn test_key_bytes_are_platform_independent() {
+        assert_eq!(vec![7, 0, 0, 0, 0, 0, 0, 0], key_bytes(&7usize));
+        assert_eq!(b"AB\xff".to_vec(), key_bytes(&"AB"));
+    }
+}
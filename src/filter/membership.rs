@@ -0,0 +1,138 @@
+use crate::clock::Clock;
+use crate::filter::config::FilterConfig;
+use std::fs;
+use std::fs::File;
+use std::hash::Hash;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct MembershipStats {
+    pub backend: &'static str,
+    pub layer_count: usize,
+    /// Memory held by the filter, in bits
+    pub total_bits: usize,
+    /// Share of bits or slots in use
+    pub fill_ratio: f64,
+    /// Share of bits or slots in use per layer, oldest first
+    pub layer_fill: Vec<f64>,
+    pub est_fpr: f64,
+}
+
+/// Set of trusted IDs behind `FilterManager`. Entries are held in layers grouped into generations, a layer
+/// ages out as a whole on `prune` once nothing was written to it for a while
+pub trait MembershipFilter: Sized {
+    fn new(config: &FilterConfig, clock: Clock) -> Self;
+
+    /// Adds `input` to the current generation, unless that already holds it
+    fn insert<T: Hash>(&mut self, input: &T);
+
+    fn contains<T: Hash>(&self, input: &T) -> bool;
+
+    /// Forgets `input`, returns false if it wasn't held or the filter can't delete
+    fn remove<T: Hash>(&mut self, input: &T) -> bool;
+
+    fn fpr(&self) -> f64;
+
+    fn stats(&self) -> MembershipStats;
+
+    /// Starts a new generation, later inserts go to a fresh layer while the older ones age out
+    fn rotate(&mut self);
+
+    /// Time since the current generation started
+    fn generation_age(&self) -> Duration;
+
+    /// Drops layers nothing was written to within `max_age`
+    fn prune(&mut self, max_age: Duration);
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// Restores a filter written by `write_to`, parts added later use `config`
+    fn read_from<R: Read>(reader: &mut R, config: &FilterConfig, clock: Clock) -> io::Result<Self>;
+
+    /// Handle ingest threads can check trusted IDs through, for backends safe to share between threads
+    fn shared(&self) -> Option<Arc<dyn SharedFilter>> {
+        None
+    }
+
+    /// Saves to `path` through a temporary file, so a crash mid-write keeps the previous state
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.write_to(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    /// Loads the state saved at `path`, or starts empty when there is none yet
+    fn load(path: &Path, config: &FilterConfig, clock: Clock) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::read_from(&mut BufReader::new(file), config, clock),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new(config, clock)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Trusted IDs checked off the consumer thread, without going through `FilterManager`
+pub trait SharedFilter: Send + Sync {
+    /// Whether `key` is trusted, refreshing it into the current generation if so
+    fn check_trusted(&self, key: &str) -> bool;
+}
+
+/// Largest layer a state file may declare, 1 GiB, so a corrupt file can't exhaust memory
+pub const MAX_LAYER_BITS: usize = 1 << 33;
+
+pub fn invalid_state(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid filter state: {msg}"))
+}
+
+/// Checks a state file starts with `magic` and `version`
+pub fn read_magic<R: Read>(reader: &mut R, magic: &[u8; 6], version: u8) -> io::Result<()> {
+    let mut header = [0; 7];
+    reader.read_exact(&mut header)?;
+    if &header[..6] != magic {
+        return Err(invalid_state("not a state file of this filter backend"));
+    }
+    if header[6] != version {
+        return Err(invalid_state(&format!("unsupported version {}", header[6])));
+    }
+    Ok(())
+}
+
+pub fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Bits of a layer of `count` parts of `part_bits` each, as declared by a state file
+pub fn layer_bits(count: usize, part_bits: usize) -> io::Result<usize> {
+    count.checked_mul(part_bits)
+        .filter(|bits| (1..=MAX_LAYER_BITS).contains(bits))
+        .ok_or_else(|| invalid_state(&format!("layer of {count} x {part_bits} bits out of range")))
+}
+
+/// Reads `len` bytes, the buffer only grows as data arrives so a truncated file can't force a large allocation
+pub fn read_bytes<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
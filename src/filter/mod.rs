@@ -1,2 +1,8 @@
 pub mod bloom_filter;
-pub mod filter_manager;
\ No newline at end of file
+pub mod concurrent_bloom_filter;
+pub mod config;
+pub mod cuckoo_filter;
+pub mod filter_manager;
+pub mod hash;
+pub mod membership;
+pub mod output;
@@ -0,0 +1,170 @@
+use crate::filter::filter_manager::FilterResult;
+use crate::simulator::model::AdsbPacket;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// `NEW`, `PROMOTED` and `MATCH` lines for reading along
+    Text,
+    /// One JSON record per packet
+    Json,
+    /// One CSV row per packet, after a header row
+    Csv,
+}
+
+/// Outcome of filtering one packet, with the filter state it was judged against. `est_fpr` is current,
+/// `observed_fpr`, `layers` and `fill_ratio` are costly to collect and date from the last prune
+#[derive(Debug, Serialize)]
+pub struct FilterRecord<'a> {
+    pub id: &'a str,
+    pub callsign: Option<&'a str>,
+    pub result: FilterResult,
+    /// Sightings of the ID inside the promotion window while it is pending
+    pub sightings: usize,
+    /// IDs pending promotion
+    pub pending: usize,
+    pub est_fpr: f64,
+    pub observed_fpr: Option<f64>,
+    pub layers: usize,
+    pub fill_ratio: f64,
+}
+
+const CSV_HEADER: &str = "id,callsign,result,sightings,pending,est_fpr,observed_fpr,layers,fill_ratio";
+
+/// Quotes a CSV field when it holds a separator, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Writes the outcome of every filtered packet, or with `passthrough` only the promoted and trusted
+/// packets themselves as JSON lines. JSON input is passed through as it was read, packets of the other
+/// formats span several input lines or frames and are serialized
+pub struct FilterOutput<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    passthrough: bool,
+    header_written: bool,
+}
+
+impl<W: Write> FilterOutput<W> {
+    pub fn new(writer: W, format: OutputFormat, passthrough: bool) -> Self {
+        Self { writer, format, passthrough, header_written: false }
+    }
+
+    pub fn write(&mut self, packet: &AdsbPacket, record: &FilterRecord) -> io::Result<()> {
+        if self.passthrough {
+            if record.result != FilterResult::Pending {
+                match &packet.raw {
+                    Some(line) => writeln!(self.writer, "{line}")?,
+                    None => {
+                        serde_json::to_writer(&mut self.writer, packet)?;
+                        writeln!(self.writer)?;
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        match self.format {
+            OutputFormat::Text => match record.result {
+                FilterResult::Pending => writeln!(self.writer, "NEW:\t{}", record.id),
+                FilterResult::Promoted => writeln!(self.writer, "PROMOTED:\t{}", record.id),
+                FilterResult::Trusted => writeln!(self.writer, "MATCH:\t{} (Est. FPR: {:.4}%)", record.id, record.est_fpr * 100.0),
+            },
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                writeln!(self.writer)
+            }
+            OutputFormat::Csv => {
+                if !self.header_written {
+                    writeln!(self.writer, "{CSV_HEADER}")?;
+                    self.header_written = true;
+                }
+                writeln!(
+                    self.writer,
+                    "{},{},{:?},{},{},{},{},{},{}",
+                    csv_field(record.id),
+                    record.callsign.map(csv_field).unwrap_or_default(),
+                    record.result,
+                    record.sightings,
+                    record.pending,
+                    record.est_fpr,
+                    record.observed_fpr.map(|fpr| fpr.to_string()).unwrap_or_default(),
+                    record.layers,
+                    record.fill_ratio,
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::packet;
+
+    fn record<'a>(packet: &'a AdsbPacket, result: FilterResult) -> FilterRecord<'a> {
+        FilterRecord {
+            id: &packet.id,
+            callsign: packet.callsign.as_deref(),
+            result,
+            sightings: 2,
+            pending: 5,
+            est_fpr: 0.001,
+            observed_fpr: None,
+            layers: 1,
+            fill_ratio: 0.25,
+        }
+    }
+
+    fn written(format: OutputFormat, passthrough: bool, results: &[FilterResult]) -> String {
+        written_packet(AdsbPacket { callsign: Some("KLM1,A".to_string()), ..packet("ABC123") }, format, passthrough, results)
+    }
+
+    fn written_packet(packet: AdsbPacket, format: OutputFormat, passthrough: bool, results: &[FilterResult]) -> String {
+        let mut output = FilterOutput::new(Vec::new(), format, passthrough);
+        for result in results {
+            output.write(&packet, &record(&packet, *result)).unwrap();
+        }
+        String::from_utf8(output.writer).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            "NEW:\tABC123\nPROMOTED:\tABC123\nMATCH:\tABC123 (Est. FPR: 0.1000%)\n",
+            written(OutputFormat::Text, false, &[FilterResult::Pending, FilterResult::Promoted, FilterResult::Trusted])
+        );
+        assert_eq!(
+            format!("{CSV_HEADER}\nABC123,\"KLM1,A\",Trusted,2,5,0.001,,1,0.25\nABC123,\"KLM1,A\",Pending,2,5,0.001,,1,0.25\n"),
+            written(OutputFormat::Csv, false, &[FilterResult::Trusted, FilterResult::Pending])
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&written(OutputFormat::Json, false, &[FilterResult::Promoted])).unwrap();
+        assert_eq!("Promoted", json["result"]);
+        assert_eq!("KLM1,A", json["callsign"]);
+        assert_eq!(5, json["pending"]);
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let out = written(OutputFormat::Csv, true, &[FilterResult::Pending, FilterResult::Promoted, FilterResult::Trusted]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(2, lines.len());
+        let passed: AdsbPacket = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!("ABC123", passed.id);
+        assert_eq!(4.0, passed.vy);
+
+        let line = r#"{"id":"ABC123","lat":52.0,"lon":4.0,"gs":250,"track":90,"alt":10000,"squawk":"7000"}"#;
+        let raw = AdsbPacket { raw: Some(line.to_string()), ..packet("ABC123") };
+        assert_eq!(format!("{line}\n"), written_packet(raw, OutputFormat::Text, true, &[FilterResult::Trusted]));
+    }
+}
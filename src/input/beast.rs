@@ -0,0 +1,212 @@
+use crate::input::mode_s::{DecodeError, ModeSDecoder};
+use crate::simulator::geo::Projection;
+use crate::simulator::model::AdsbPacket;
+use std::io;
+use std::io::BufRead;
+
+const ESCAPE: u8 = 0x1A;
+/// MLAT timestamps count ticks of the receiver's 12 MHz clock
+const TICKS_PER_SECOND: f64 = 12_000_000.0;
+
+#[derive(Debug, PartialEq)]
+pub struct BeastFrame {
+    /// `'1'` Mode A/C, `'2'` Mode S short, `'3'` Mode S long
+    pub kind: u8,
+    pub timestamp: u64,
+    pub signal: u8,
+    pub data: Vec<u8>,
+}
+
+fn payload_len(kind: u8) -> Option<usize> {
+    match kind {
+        b'1' => Some(2),
+        b'2' => Some(7),
+        b'3' => Some(14),
+        _ => None,
+    }
+}
+
+fn read_byte<R: BufRead>(reader: &mut R) -> io::Result<Option<u8>> {
+    let byte = match reader.fill_buf()?.first() {
+        Some(b) => *b,
+        None => return Ok(None),
+    };
+    reader.consume(1);
+    Ok(Some(byte))
+}
+
+/// Reads the next frame, skipping garbage and unknown frame types until the stream resyncs
+pub fn read_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<BeastFrame>> {
+    let mut pending_kind = None;
+
+    'frame: loop {
+        let kind = match pending_kind.take() {
+            Some(kind) => kind,
+            None => {
+                loop {
+                    match read_byte(reader)? {
+                        Some(ESCAPE) => break,
+                        Some(_) => continue,
+                        None => return Ok(None),
+                    }
+                }
+                match read_byte(reader)? {
+                    Some(kind) => kind,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let Some(len) = payload_len(kind) else {
+            continue;
+        };
+
+        let mut payload = Vec::with_capacity(7 + len);
+        while payload.len() < 7 + len {
+            let Some(byte) = read_byte(reader)? else {
+                return Ok(None);
+            };
+            if byte == ESCAPE {
+                match read_byte(reader)? {
+                    Some(ESCAPE) => {}
+                    Some(next) => {
+                        // unescaped marker, the current frame was truncated
+                        pending_kind = Some(next);
+                        continue 'frame;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            payload.push(byte);
+        }
+
+        return Ok(Some(BeastFrame {
+            kind,
+            timestamp: payload[..6].iter().fold(0u64, |acc, &b| acc << 8 | b as u64),
+            signal: payload[6],
+            data: payload.split_off(7),
+        }));
+    }
+}
+
+pub struct BeastDecoder {
+    mode_s: ModeSDecoder,
+}
+
+impl BeastDecoder {
+    pub fn new(projection: Projection) -> Self {
+        Self {
+            mode_s: ModeSDecoder::new(projection),
+        }
+    }
+
+    /// Decodes the Mode S payload, tagging packets with the receiver timestamp and signal level. Frames
+    /// without an MLAT timestamp carry no `ts`, their CPR pairing falls back to the decoder's own clock
+    pub fn decode(&mut self, frame: &BeastFrame) -> Result<Option<AdsbPacket>, DecodeError> {
+        if frame.kind != b'3' {
+            return Ok(None);
+        }
+
+        if frame.timestamp == 0 {
+            return Ok(self.mode_s.decode(&frame.data)?.map(|packet| AdsbPacket {
+                signal: Some(frame.signal),
+                ..packet
+            }));
+        }
+
+        let ts = frame.timestamp as f64 / TICKS_PER_SECOND;
+        Ok(self.mode_s.decode_at(&frame.data, ts)?.map(|packet| AdsbPacket {
+            ts: Some(ts),
+            signal: Some(frame.signal),
+            ..packet
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::mode_s::parse_hex;
+    use crate::simulator::geo::GeoPoint;
+    use std::io::Cursor;
+
+    fn encode(kind: u8, timestamp: u64, signal: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![ESCAPE, kind];
+        for byte in timestamp.to_be_bytes()[2..].iter().chain([signal].iter()).chain(data) {
+            out.push(*byte);
+            if *byte == ESCAPE {
+                out.push(ESCAPE);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let long = parse_hex("8D4840D6202CC371C32CE0576098").unwrap();
+        let mut stream = vec![0x00, 0x42];
+        stream.extend(encode(b'2', 0x1A, 0x1A, &[0x5D, 0x48, 0x40, 0xD6, 0xA6, 0xB2, 0xC0]));
+        stream.extend([ESCAPE, b'4', 0x01, 0x02]);
+        stream.extend(encode(b'3', 0x0102_0304_0506, 200, &long));
+
+        let mut reader = Cursor::new(stream);
+
+        let short = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(b'2', short.kind);
+        assert_eq!(0x1A, short.timestamp);
+        assert_eq!(0x1A, short.signal);
+        assert_eq!(7, short.data.len());
+
+        assert_eq!(
+            Some(BeastFrame { kind: b'3', timestamp: 0x0102_0304_0506, signal: 200, data: long }),
+            read_frame(&mut reader).unwrap()
+        );
+        assert_eq!(None, read_frame(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn test_resync_on_truncated_frame() {
+        let long = parse_hex("8D4840D6202CC371C32CE0576098").unwrap();
+        let mut stream = encode(b'3', 1, 1, &long);
+        stream.truncate(10);
+        stream.extend(encode(b'3', 2, 2, &long));
+
+        let frame = read_frame(&mut Cursor::new(stream)).unwrap().unwrap();
+        assert_eq!(2, frame.timestamp);
+        assert_eq!(long, frame.data);
+    }
+
+    #[test]
+    fn test_decode_uses_receiver_time() {
+        let mut decoder = BeastDecoder::new(Projection::new(GeoPoint::new(52.2572, 3.91937)));
+        let frames = [
+            ("8D40621D58C386435CC412692AD6", 12_000_000),
+            ("8D40621D58C382D690C8AC2863A7", 36_000_000),
+            ("8D40621D994409940838174550B1", 48_000_000),
+        ];
+
+        let packets: Vec<_> = frames.iter()
+            .map(|(hex, ts)| BeastFrame { kind: b'3', timestamp: *ts, signal: 99, data: parse_hex(hex).unwrap() })
+            .filter_map(|frame| decoder.decode(&frame).unwrap())
+            .collect();
+
+        // even and odd frames arrive within the pairing window in receiver time
+        assert_eq!(1, packets.len());
+        assert_eq!(Some(4.0), packets[0].ts);
+        assert_eq!(Some(99), packets[0].signal);
+    }
+
+    #[test]
+    fn test_decode_without_timestamp() {
+        let mut decoder = BeastDecoder::new(Projection::new(GeoPoint::new(52.2572, 3.91937)));
+        let packets: Vec<_> = ["8D40621D58C386435CC412692AD6", "8D40621D58C382D690C8AC2863A7", "8D40621D994409940838174550B1"]
+            .iter()
+            .map(|hex| BeastFrame { kind: b'3', timestamp: 0, signal: 99, data: parse_hex(hex).unwrap() })
+            .filter_map(|frame| decoder.decode(&frame).unwrap())
+            .collect();
+
+        // no MLAT counter, so no event time either
+        assert_eq!(1, packets.len());
+        assert_eq!(None, packets[0].ts);
+    }
+}
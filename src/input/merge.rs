@@ -0,0 +1,138 @@
+use crate::clock::Clock;
+use crate::input::source_metrics::SourceMetrics;
+use crate::simulator::model::AdsbPacket;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Collapses reports of the same aircraft arriving from different receivers within a short window,
+/// so overlapping coverage doesn't multiply the sighting rate seen by the filter. The window is
+/// measured on the pipeline clock, so files and replays are de-duplicated in event time
+pub struct Deduplicator {
+    window: Duration,
+    last_accepted: HashMap<String, (Option<Arc<str>>, Duration)>,
+    last_sweep: Duration,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_accepted: HashMap::new(),
+            last_sweep: Duration::ZERO,
+        }
+    }
+
+    /// Whether a report seen at `seen` still holds off other receivers at `now`. Time going back
+    /// means the clock restarted for a rewound replay
+    fn within_window(&self, seen: Duration, now: Duration) -> bool {
+        now.checked_sub(seen).is_some_and(|age| age < self.window)
+    }
+
+    /// Reports from the receiver that was last accepted for an aircraft always pass,
+    /// other receivers only take over once it has been silent for the whole window
+    pub fn accept(&mut self, packet: &AdsbPacket, now: Duration) -> bool {
+        if now.abs_diff(self.last_sweep) > self.window * 10 {
+            let window = self.window;
+            self.last_accepted.retain(|_, (_, seen)| now.checked_sub(*seen).is_some_and(|age| age < window));
+            self.last_sweep = now;
+        }
+
+        if let Some((receiver, seen)) = self.last_accepted.get(&packet.id)
+            && *receiver != packet.receiver
+            && self.within_window(*seen, now) {
+            return false;
+        }
+
+        self.last_accepted.insert(packet.id.clone(), (packet.receiver.clone(), now));
+        true
+    }
+}
+
+/// Forwards packets from all sources into a single channel, dropping cross-receiver duplicates.
+/// `clock` is advanced by the packets as they pass, it must not be the consumer's which lags behind
+pub fn merge(rx: Receiver<AdsbPacket>, window: Duration, metrics: Arc<SourceMetrics>, clock: Clock) -> Receiver<AdsbPacket> {
+    let (tx, merged) = channel();
+
+    thread::spawn(move || {
+        let mut dedup = Deduplicator::new(window);
+        for packet in rx {
+            if let Some(ts) = packet.ts {
+                clock.observe(packet.receiver.as_deref(), ts);
+            }
+            if !dedup.accept(&packet, clock.now()) {
+                metrics.duplicates.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if tx.send(packet).is_err() {
+                return;
+            }
+        }
+    });
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::received;
+
+    #[test]
+    fn test_collapses_overlapping_receivers() {
+        let mut dedup = Deduplicator::new(Duration::from_millis(1000));
+        let at = Duration::from_millis;
+
+        assert!(dedup.accept(&received("A1", "rx1"), at(0)));
+        assert!(!dedup.accept(&received("A1", "rx2"), at(10)));
+        assert!(!dedup.accept(&received("A1", "rx3"), at(20)));
+        assert!(dedup.accept(&received("B2", "rx2"), at(30)));
+
+        // the accepted receiver keeps reporting at its own rate
+        assert!(dedup.accept(&received("A1", "rx1"), at(500)));
+        assert!(!dedup.accept(&received("A1", "rx2"), at(1400)));
+
+        // rx1 went silent, rx2 takes over
+        assert!(dedup.accept(&received("A1", "rx2"), at(1600)));
+        assert!(!dedup.accept(&received("A1", "rx1"), at(1700)));
+
+        // a rewound replay restarts the clock
+        assert!(dedup.accept(&received("A1", "rx1"), at(100)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let (tx, rx) = channel();
+        let metrics = Arc::new(SourceMetrics::new());
+        let merged = merge(rx, Duration::from_secs(60), metrics.clone(), Clock::new());
+
+        for receiver in ["rx1", "rx2", "rx3"] {
+            tx.send(received("A1", receiver)).unwrap();
+        }
+        tx.send(received("A1", "rx1")).unwrap();
+        drop(tx);
+
+        assert_eq!(2, merged.iter().count());
+        assert_eq!(2, metrics.duplicates.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_merge_in_event_time() {
+        let (tx, rx) = channel();
+        let metrics = Arc::new(SourceMetrics::new());
+        let clock = Clock::new();
+        let merged = merge(rx, Duration::from_secs(1), metrics.clone(), clock.clone());
+
+        // a capture read in one go, rx2 reports 5 s after rx1 went silent
+        for (receiver, ts) in [("rx1", 0.0), ("rx2", 0.5), ("rx1", 0.8), ("rx2", 5.0)] {
+            tx.send(AdsbPacket { ts: Some(1_700_000_000.0 + ts), ..received("A1", receiver) }).unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(vec!["rx1", "rx1", "rx2"], merged.iter().map(|p| p.receiver.unwrap().to_string()).collect::<Vec<_>>());
+        assert_eq!(1, metrics.duplicates.load(Ordering::Relaxed));
+    }
+}
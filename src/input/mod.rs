@@ -0,0 +1,169 @@
+pub mod beast;
+pub mod merge;
+pub mod mode_s;
+pub mod record;
+pub mod reject_log;
+pub mod replay;
+pub mod sbs;
+pub mod source;
+pub mod source_metrics;
+
+use crate::input::beast::BeastDecoder;
+use crate::input::mode_s::ModeSDecoder;
+use crate::input::sbs::{SbsDecoder, SbsError};
+use crate::simulator::geo::Projection;
+use crate::simulator::model::{AdsbPacket, JsonPacket};
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// One JSON packet per line, Cartesian (px/py/vx/vy) or geodetic (lat/lon/gs/track)
+    Json,
+    /// Mode S frames as hex, one per line (`*8D4840D6202CC371C32CE0576098;`)
+    Raw,
+    /// BaseStation `MSG,1..8` CSV rows (port 30003)
+    Sbs,
+    /// Mode-S Beast binary frames
+    Beast,
+}
+
+/// Why an input line was dropped
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// The line couldn't be decoded
+    Malformed(String),
+    /// The line decoded to a physically impossible packet
+    Implausible(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Malformed(error) => write!(f, "malformed: {error}"),
+            Rejection::Implausible(error) => write!(f, "implausible: {error}"),
+        }
+    }
+}
+
+fn validate(packet: Option<AdsbPacket>) -> Result<Option<AdsbPacket>, Rejection> {
+    match packet {
+        Some(packet) => packet.validate().map(|_| Some(packet)).map_err(Rejection::Implausible),
+        None => Ok(None),
+    }
+}
+
+pub enum Decoder {
+    Json(Projection),
+    Raw(ModeSDecoder),
+    Sbs(SbsDecoder),
+    Beast(BeastDecoder),
+}
+
+impl Decoder {
+    pub fn new(format: InputFormat, projection: Projection) -> Self {
+        match format {
+            InputFormat::Json => Decoder::Json(projection),
+            InputFormat::Raw => Decoder::Raw(ModeSDecoder::new(projection)),
+            InputFormat::Sbs => Decoder::Sbs(SbsDecoder::new(projection)),
+            InputFormat::Beast => Decoder::Beast(BeastDecoder::new(projection)),
+        }
+    }
+
+    /// Decodes a line, `Ok(None)` for lines that carry no position like blank lines or non-MSG SBS rows
+    pub fn decode_line(&mut self, line: &str) -> Result<Option<AdsbPacket>, Rejection> {
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+        let packet = match self {
+            Decoder::Json(projection) => JsonPacket::parse(line)
+                .map(|packet| Some(AdsbPacket { raw: Some(line.trim_end().to_string()), ..packet.into_packet(projection) }))
+                .map_err(|e| Rejection::Malformed(e.to_string()))?,
+            Decoder::Raw(decoder) => decoder.decode_hex(line).map_err(|e| Rejection::Malformed(e.to_string()))?,
+            Decoder::Sbs(decoder) => match decoder.decode_line(line) {
+                Err(SbsError::NotAMessage) => None,
+                result => result.map_err(|e| Rejection::Malformed(e.to_string()))?,
+            },
+            // binary frames are read by process_adsb_stream
+            Decoder::Beast(_) => None,
+        };
+        validate(packet)
+    }
+}
+
+/// Decodes every packet in `reader`, handing rejected lines to `reject` along with the reason
+pub fn process_adsb_stream<R, F, E>(mut reader: R, decoder: &mut Decoder, mut action: F, mut reject: E) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(AdsbPacket),
+    E: FnMut(&str, Rejection),
+{
+    if let Decoder::Beast(beast) = decoder {
+        while let Some(frame) = beast::read_frame(&mut reader)? {
+            let decoded = beast.decode(&frame).map_err(|e| Rejection::Malformed(e.to_string()));
+            match decoded.and_then(validate) {
+                Ok(Some(packet)) => action(packet),
+                Ok(None) => {}
+                Err(rejection) => {
+                    let hex: String = frame.data.iter().map(|b| format!("{b:02X}")).collect();
+                    reject(&hex, rejection);
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    let mut buf = String::new();
+
+    while reader.read_line(&mut buf)? > 0 {
+        match decoder.decode_line(&buf) {
+            Ok(Some(packet)) => action(packet),
+            Ok(None) => {}
+            Err(rejection) => reject(buf.trim_end(), rejection),
+        }
+
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::geo::GeoPoint;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_rejections() {
+        let input = [
+            r#"{"id":"A1","callsign":null,"px":1.0,"py":2.0,"vx":3.0,"vy":4.0,"alt":10000}"#,
+            "",
+            r#"{"id":"A2","px":1.0}"#,
+            r#"{"id":"A3","callsign":null,"px":1.0,"py":2.0,"vx":900.0,"vy":4.0,"alt":10000}"#,
+        ].join("\n");
+        let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+
+        let mut packets = Vec::new();
+        let mut rejections = Vec::new();
+        process_adsb_stream(Cursor::new(input), &mut decoder, |p| packets.push(p), |line, r| rejections.push((line.to_string(), r))).unwrap();
+
+        assert_eq!(vec!["A1"], packets.iter().map(|p| p.id.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(r#"{"id":"A1","callsign":null,"px":1.0,"py":2.0,"vx":3.0,"vy":4.0,"alt":10000}"#), packets[0].raw.as_deref());
+        assert_eq!(2, rejections.len());
+        assert_eq!(r#"{"id":"A2","px":1.0}"#, rejections[0].0);
+        assert!(matches!(&rejections[0].1, Rejection::Malformed(e) if e.contains("missing field")), "{:?}", rejections[0].1);
+        assert!(matches!(rejections[1].1, Rejection::Implausible(_)));
+    }
+
+    #[test]
+    fn test_sbs_skips_non_message_rows() {
+        let mut decoder = Decoder::new(InputFormat::Sbs, Projection::new(GeoPoint::new(0.0, 0.0)));
+        assert_eq!(Ok(None), decoder.decode_line("STA,,5,179,400AE7,10103,2008/11/28,14:58:51.153,2008/11/28,14:58:51.153,RM").map(|p| p.map(|p| p.id)));
+        assert!(decoder.decode_line("MSG,x").is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use crate::simulator::geo::{GeoPoint, Projection, FPM_TO_MS, KNOTS_TO_MS};
+use crate::simulator::math::Vector2D;
+use crate::simulator::model::AdsbPacket;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::time::Instant;
+
+const CRC24_POLY: u32 = 0xFFF409;
+const CALLSIGN_CHARS: &[u8; 64] = b"#ABCDEFGHIJKLMNOPQRSTUVWXYZ##### ###############0123456789######";
+const METRES_TO_FEET: f64 = 3.28084;
+const CPR_MAX: f64 = 131_072.0;
+const CPR_NZ: f64 = 15.0;
+/// Even and odd position frames further apart than this can't be paired for a global decode
+const CPR_PAIR_WINDOW: f64 = 10.0;
+/// Tracks silent for longer than this lose their state
+const TRACK_TIMEOUT: f64 = 60.0;
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    InvalidHex,
+    InvalidLength(usize),
+    Crc { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidHex => write!(f, "invalid hex frame"),
+            DecodeError::InvalidLength(len) => write!(f, "invalid frame length: {len} bytes"),
+            DecodeError::Crc { expected, actual } => write!(f, "CRC mismatch: expected {expected:06X}, got {actual:06X}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Identification { callsign: String },
+    AirbornePosition { altitude: Option<f64>, odd: bool, lat_cpr: u32, lon_cpr: u32 },
+    AirborneVelocity { velocity: Vector2D, vertical_rate: Option<f64> },
+    Unsupported { type_code: u8 },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub icao: u32,
+    pub message: Message,
+}
+
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x100_0000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0xFF_FFFF
+}
+
+/// Parses an AVR style hex frame (`*8D...;`, `@<timestamp>8D...;` or bare hex) into bytes
+pub fn parse_hex(line: &str) -> Result<Vec<u8>, DecodeError> {
+    let line = line.trim();
+    let hex = if let Some(rest) = line.strip_prefix('@') {
+        rest.get(12..).ok_or(DecodeError::InvalidHex)?
+    } else {
+        line.strip_prefix('*').unwrap_or(line)
+    };
+    let hex = hex.strip_suffix(';').unwrap_or(hex);
+
+    if hex.len() % 2 != 0 {
+        return Err(DecodeError::InvalidHex);
+    }
+
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2)
+            .and_then(|b| u8::from_str_radix(b, 16).ok())
+            .ok_or(DecodeError::InvalidHex))
+        .collect()
+}
+
+/// Parses a DF17/18 extended squitter, returns `Ok(None)` for other downlink formats
+pub fn parse_frame(data: &[u8]) -> Result<Option<Frame>, DecodeError> {
+    match data.len() {
+        7 => return Ok(None),
+        14 => {}
+        len => return Err(DecodeError::InvalidLength(len)),
+    }
+
+    let df = data[0] >> 3;
+    let cf = data[0] & 0x07;
+    if df != 17 && !(df == 18 && cf == 0) {
+        return Ok(None);
+    }
+
+    let expected = crc24(&data[..11]);
+    let actual = (data[11] as u32) << 16 | (data[12] as u32) << 8 | data[13] as u32;
+    if expected != actual {
+        return Err(DecodeError::Crc { expected, actual });
+    }
+
+    let icao = (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32;
+    let me = data[4..11].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+    let type_code = (me >> 51) as u8;
+
+    let message = match type_code {
+        1..=4 => Message::Identification { callsign: decode_callsign(me) },
+        9..=18 | 20..=22 => Message::AirbornePosition {
+            altitude: decode_altitude(((me >> 36) & 0xFFF) as u32, type_code >= 20),
+            odd: (me >> 34) & 1 == 1,
+            lat_cpr: ((me >> 17) & 0x1FFFF) as u32,
+            lon_cpr: (me & 0x1FFFF) as u32,
+        },
+        19 => match decode_velocity(me) {
+            Some((velocity, vertical_rate)) => Message::AirborneVelocity { velocity, vertical_rate },
+            None => Message::Unsupported { type_code },
+        },
+        _ => Message::Unsupported { type_code },
+    };
+
+    Ok(Some(Frame { icao, message }))
+}
+
+fn decode_callsign(me: u64) -> String {
+    (0..8)
+        .map(|i| CALLSIGN_CHARS[((me >> (42 - i * 6)) & 0x3F) as usize] as char)
+        .filter(|c| *c != '#')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn decode_altitude(raw: u32, gnss: bool) -> Option<f64> {
+    if raw == 0 {
+        return None;
+    }
+    if gnss {
+        return Some(raw as f64 * METRES_TO_FEET);
+    }
+    // Gillham coded altitudes (Q bit clear) are not supported
+    if raw & 0x10 == 0 {
+        return None;
+    }
+    let n = ((raw & 0xFE0) >> 1) | (raw & 0x0F);
+    Some(n as f64 * 25.0 - 1000.0)
+}
+
+fn decode_velocity(me: u64) -> Option<(Vector2D, Option<f64>)> {
+    let subtype = ((me >> 48) & 0x07) as u8;
+    let vertical_rate = match (me >> 10) & 0x1FF {
+        0 => None,
+        vr => {
+            let sign = if (me >> 19) & 1 == 1 { -1.0 } else { 1.0 };
+            Some(sign * (vr as f64 - 1.0) * 64.0)
+        }
+    };
+
+    let velocity = match subtype {
+        1 | 2 => {
+            let factor = if subtype == 2 { 4.0 } else { 1.0 };
+            let v_ew = (me >> 32) & 0x3FF;
+            let v_ns = (me >> 21) & 0x3FF;
+            if v_ew == 0 || v_ns == 0 {
+                return None;
+            }
+            let sign_ew = if (me >> 42) & 1 == 1 { -1.0 } else { 1.0 };
+            let sign_ns = if (me >> 31) & 1 == 1 { -1.0 } else { 1.0 };
+            Vector2D::new(
+                sign_ew * (v_ew as f64 - 1.0) * factor * KNOTS_TO_MS,
+                sign_ns * (v_ns as f64 - 1.0) * factor * KNOTS_TO_MS,
+            )
+        }
+        3 | 4 => {
+            // airspeed and heading only, used as a ground velocity approximation
+            let factor = if subtype == 4 { 4.0 } else { 1.0 };
+            let airspeed = (me >> 21) & 0x3FF;
+            if (me >> 42) & 1 == 0 || airspeed == 0 {
+                return None;
+            }
+            let heading = (((me >> 32) & 0x3FF) as f64 * 360.0 / 1024.0).to_radians();
+            let speed = (airspeed as f64 - 1.0) * factor * KNOTS_TO_MS;
+            Vector2D::new(speed * heading.sin(), speed * heading.cos())
+        }
+        _ => return None,
+    };
+
+    Some((velocity, vertical_rate))
+}
+
+fn modulo(a: f64, b: f64) -> f64 {
+    a - b * (a / b).floor()
+}
+
+fn nl(lat: f64) -> f64 {
+    let lat = lat.abs();
+    if lat == 0.0 {
+        return 59.0;
+    }
+    if lat == 87.0 {
+        return 2.0;
+    }
+    if lat > 87.0 {
+        return 1.0;
+    }
+    let a = 1.0 - (PI / (2.0 * CPR_NZ)).cos();
+    let b = lat.to_radians().cos().powi(2);
+    (2.0 * PI / (1.0 - a / b).acos()).floor()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CprFrame {
+    lat: f64,
+    lon: f64,
+    time: f64,
+}
+
+impl CprFrame {
+    fn new(lat_cpr: u32, lon_cpr: u32, time: f64) -> Self {
+        Self { lat: lat_cpr as f64 / CPR_MAX, lon: lon_cpr as f64 / CPR_MAX, time }
+    }
+}
+
+fn cpr_global(even: CprFrame, odd: CprFrame) -> Option<GeoPoint> {
+    let dlat_even = 360.0 / (4.0 * CPR_NZ);
+    let dlat_odd = 360.0 / (4.0 * CPR_NZ - 1.0);
+    let j = (59.0 * even.lat - 60.0 * odd.lat + 0.5).floor();
+
+    let mut lat_even = dlat_even * (modulo(j, 60.0) + even.lat);
+    let mut lat_odd = dlat_odd * (modulo(j, 59.0) + odd.lat);
+    if lat_even >= 270.0 {
+        lat_even -= 360.0;
+    }
+    if lat_odd >= 270.0 {
+        lat_odd -= 360.0;
+    }
+    if nl(lat_even) != nl(lat_odd) {
+        return None;
+    }
+
+    let odd_latest = odd.time > even.time;
+    let (lat, nl_lat, lon_cpr) = if odd_latest {
+        (lat_odd, nl(lat_odd), odd.lon)
+    } else {
+        (lat_even, nl(lat_even), even.lon)
+    };
+    let ni = if odd_latest { (nl_lat - 1.0).max(1.0) } else { nl_lat.max(1.0) };
+    let m = (even.lon * (nl_lat - 1.0) - odd.lon * nl_lat + 0.5).floor();
+
+    let mut lon = (360.0 / ni) * (modulo(m, ni) + lon_cpr);
+    if lon >= 180.0 {
+        lon -= 360.0;
+    }
+
+    Some(GeoPoint::new(lat, lon))
+}
+
+fn cpr_local(frame: CprFrame, odd: bool, reference: GeoPoint) -> GeoPoint {
+    let i = if odd { 1.0 } else { 0.0 };
+    let dlat = 360.0 / (4.0 * CPR_NZ - i);
+    let j = (reference.lat / dlat).floor()
+        + (modulo(reference.lat, dlat) / dlat - frame.lat + 0.5).floor();
+    let lat = dlat * (j + frame.lat);
+
+    let dlon = 360.0 / (nl(lat) - i).max(1.0);
+    let m = (reference.lon / dlon).floor()
+        + (modulo(reference.lon, dlon) / dlon - frame.lon + 0.5).floor();
+    let mut lon = dlon * (m + frame.lon);
+    if lon >= 180.0 {
+        lon -= 360.0;
+    }
+
+    GeoPoint::new(lat, lon)
+}
+
+#[derive(Default)]
+struct Track {
+    callsign: Option<String>,
+    even: Option<CprFrame>,
+    odd: Option<CprFrame>,
+    position: Option<GeoPoint>,
+    altitude: Option<f64>,
+    velocity: Option<Vector2D>,
+    /// ft/min
+    vertical_rate: Option<f64>,
+    last_update: f64,
+}
+
+/// Assembles DF17/18 frames into complete packets per ICAO address
+pub struct ModeSDecoder {
+    projection: Projection,
+    tracks: HashMap<u32, Track>,
+    epoch: Instant,
+    last_sweep: f64,
+}
+
+impl ModeSDecoder {
+    pub fn new(projection: Projection) -> Self {
+        Self {
+            projection,
+            tracks: HashMap::new(),
+            epoch: Instant::now(),
+            last_sweep: 0.0,
+        }
+    }
+
+    pub fn decode_hex(&mut self, line: &str) -> Result<Option<AdsbPacket>, DecodeError> {
+        let data = parse_hex(line)?;
+        self.decode(&data)
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Result<Option<AdsbPacket>, DecodeError> {
+        let now = self.epoch.elapsed().as_secs_f64();
+        self.decode_at(data, now)
+    }
+
+    /// Decodes a frame received at `now` seconds, emitting a packet once position,
+    /// altitude and velocity of the aircraft are all known
+    pub fn decode_at(&mut self, data: &[u8], now: f64) -> Result<Option<AdsbPacket>, DecodeError> {
+        let Some(frame) = parse_frame(data)? else {
+            return Ok(None);
+        };
+
+        if now - self.last_sweep > TRACK_TIMEOUT {
+            self.tracks.retain(|_, t| now - t.last_update < TRACK_TIMEOUT);
+            self.last_sweep = now;
+        }
+
+        let track = self.tracks.entry(frame.icao).or_default();
+        track.last_update = now;
+
+        match frame.message {
+            Message::Identification { callsign } => {
+                track.callsign = Some(callsign);
+                return Ok(None);
+            }
+            Message::AirbornePosition { altitude, odd, lat_cpr, lon_cpr } => {
+                let cpr = CprFrame::new(lat_cpr, lon_cpr, now);
+                if odd {
+                    track.odd = Some(cpr);
+                } else {
+                    track.even = Some(cpr);
+                }
+                if altitude.is_some() {
+                    track.altitude = altitude;
+                }
+
+                track.position = match (track.even, track.odd, track.position) {
+                    (Some(even), Some(odd), _) if (even.time - odd.time).abs() <= CPR_PAIR_WINDOW => {
+                        cpr_global(even, odd).or(track.position)
+                    }
+                    (_, _, Some(last)) => Some(cpr_local(cpr, odd, last)),
+                    _ => None,
+                };
+            }
+            Message::AirborneVelocity { velocity, vertical_rate } => {
+                track.velocity = Some(velocity);
+                track.vertical_rate = vertical_rate;
+            }
+            Message::Unsupported { .. } => return Ok(None),
+        }
+
+        Ok(Self::to_packet(frame.icao, track, &self.projection))
+    }
+
+    fn to_packet(icao: u32, track: &Track, projection: &Projection) -> Option<AdsbPacket> {
+        let (position, velocity, alt) = (track.position?, track.velocity?, track.altitude?);
+        let p = projection.project(position);
+
+        Some(AdsbPacket {
+            id: format!("{icao:06X}"),
+            callsign: track.callsign.clone(),
+            px: p.x,
+            py: p.y,
+            vx: velocity.x,
+            vy: velocity.y,
+            vz: track.vertical_rate.unwrap_or(0.0) * FPM_TO_MS,
+            alt,
+            ts: None,
+            signal: None,
+            receiver: None,
+            trusted: false,
+            raw: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc() {
+        let data = parse_hex("*8D4840D6202CC371C32CE0576098;").unwrap();
+        assert_eq!(0x576098, crc24(&data[..11]));
+        assert_eq!(0, crc24(&data));
+
+        let mut corrupted = data.clone();
+        corrupted[5] ^= 0x10;
+        assert!(matches!(parse_frame(&corrupted), Err(DecodeError::Crc { .. })));
+    }
+
+    #[test]
+    fn test_identification() {
+        let frame = parse_frame(&parse_hex("8D4840D6202CC371C32CE0576098").unwrap()).unwrap().unwrap();
+
+        assert_eq!(0x4840D6, frame.icao);
+        assert_eq!(Message::Identification { callsign: "KLM1023".to_string() }, frame.message);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let frame = parse_frame(&parse_hex("*8D485020994409940838175B284F;").unwrap()).unwrap().unwrap();
+
+        let Message::AirborneVelocity { velocity, vertical_rate } = frame.message else {
+            panic!("unexpected message {:?}", frame.message);
+        };
+        let speed = velocity.length_sq().sqrt() / KNOTS_TO_MS;
+        let track = velocity.x.atan2(velocity.y).to_degrees().rem_euclid(360.0);
+        assert!((speed - 159.20).abs() < 0.01, "speed {speed}");
+        assert!((track - 182.88).abs() < 0.01, "track {track}");
+        assert_eq!(Some(-832.0), vertical_rate);
+    }
+
+    #[test]
+    fn test_cpr_global() {
+        let even = parse_frame(&parse_hex("8D40621D58C382D690C8AC2863A7").unwrap()).unwrap().unwrap();
+        let odd = parse_frame(&parse_hex("8D40621D58C386435CC412692AD6").unwrap()).unwrap().unwrap();
+
+        let (Message::AirbornePosition { altitude, lat_cpr: lat_e, lon_cpr: lon_e, odd: false },
+            Message::AirbornePosition { lat_cpr: lat_o, lon_cpr: lon_o, odd: true, .. }) = (even.message, odd.message) else {
+            panic!("unexpected messages");
+        };
+        assert_eq!(Some(38000.0), altitude);
+
+        let position = cpr_global(CprFrame::new(lat_e, lon_e, 2.0), CprFrame::new(lat_o, lon_o, 0.0)).unwrap();
+        assert!((position.lat - 52.25720).abs() < 1e-4, "lat {}", position.lat);
+        assert!((position.lon - 3.91937).abs() < 1e-4, "lon {}", position.lon);
+
+        let local = cpr_local(CprFrame::new(lat_e, lon_e, 2.0), false, GeoPoint::new(52.258, 3.918));
+        assert!((local.lat - position.lat).abs() < 1e-6);
+        assert!((local.lon - position.lon).abs() < 1e-6);
+    }
+
+    fn with_icao(hex: &str, icao: u32) -> Vec<u8> {
+        let mut data = parse_hex(hex).unwrap();
+        data[1..4].copy_from_slice(&icao.to_be_bytes()[1..]);
+        let crc = crc24(&data[..11]);
+        data[11..].copy_from_slice(&crc.to_be_bytes()[1..]);
+        data
+    }
+
+    #[test]
+    fn test_decoder_assembles_packet() {
+        let mut decoder = ModeSDecoder::new(Projection::new(GeoPoint::new(52.2572, 3.91937)));
+
+        let odd = parse_hex("8D40621D58C386435CC412692AD6").unwrap();
+        let even = parse_hex("8D40621D58C382D690C8AC2863A7").unwrap();
+        let velocity = with_icao("8D485020994409940838175B284F", 0x40621D);
+        let identification = with_icao("8D4840D6202CC371C32CE0576098", 0x40621D);
+
+        assert!(decoder.decode_at(&identification, 0.0).unwrap().is_none());
+        assert!(decoder.decode_at(&odd, 0.0).unwrap().is_none());
+        assert!(decoder.decode_at(&even, 2.0).unwrap().is_none());
+
+        let packet = decoder.decode_at(&velocity, 3.0).unwrap().unwrap();
+        assert_eq!("40621D", packet.id);
+        assert_eq!(Some("KLM1023".to_string()), packet.callsign);
+        assert_eq!(38000.0, packet.alt);
+        assert!(packet.px.abs() < 10.0 && packet.py.abs() < 10.0, "({}, {})", packet.px, packet.py);
+        assert!(packet.vy < -80.0, "vy {}", packet.vy);
+        assert!((packet.vz + 832.0 * FPM_TO_MS).abs() < 1e-6, "vz {}", packet.vz);
+
+        // a lone frame long after the pair falls back to local decoding
+        assert!(decoder.decode_at(&odd, 30.0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_ignores_other_formats() {
+        assert_eq!(Ok(None), parse_frame(&parse_hex("*5D4840D6A6B2C0;").unwrap()));
+        assert_eq!(Err(DecodeError::InvalidLength(3)), parse_frame(&[0x8D, 0x48, 0x40]));
+        assert_eq!(Err(DecodeError::InvalidHex), parse_hex("*8D48ZZ;"));
+    }
+}
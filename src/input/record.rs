@@ -0,0 +1,214 @@
+use crate::simulator::model::AdsbPacket;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Opens a capture file, transparently decompressing `.gz` captures
+pub fn open_capture(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    if is_gzip(path) {
+        // appended recordings are concatenated gzip members
+        Ok(Box::new(MultiGzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Wall-clock time a recorded packet was received, read back from the JSON line it was decoded from
+pub fn received(packet: &AdsbPacket) -> Option<f64> {
+    #[derive(Deserialize)]
+    struct Received {
+        received: Option<f64>,
+    }
+    let raw = packet.raw.as_deref()?;
+    serde_json::from_str::<Received>(raw).ok()?.received.filter(|t| t.is_finite())
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(flatten)]
+    packet: &'a AdsbPacket,
+    /// Wall-clock receive time in seconds since the unix epoch
+    received: f64,
+}
+
+struct Capture {
+    file: File,
+    gzip: bool,
+    buffer: Vec<u8>,
+}
+
+impl Capture {
+    /// Writes out the buffered records. Compressed captures get a complete gzip member per flush,
+    /// so a recording that is killed still decompresses up to its last flush
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let buffer = std::mem::take(&mut self.buffer);
+        if self.gzip {
+            let mut encoder = GzEncoder::new(&mut self.file, Compression::default());
+            encoder.write_all(&buffer)?;
+            encoder.finish()?;
+        } else {
+            self.file.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends every received packet to a JSON lines capture that `replay` can play back. Shared by
+/// all source threads, a write error is kept for the consumer to report and stops the recording.
+/// The records are written out every `FLUSH_INTERVAL`, whether packets keep arriving or not
+pub struct Recorder {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    capture: Mutex<Option<Capture>>,
+    error: OnceLock<String>,
+}
+
+impl Recorder {
+    /// Opens `path` for appending, gzip-compressed when it ends in `.gz`, and starts flushing it
+    /// until the recorder is dropped
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let capture = Capture { file, gzip: is_gzip(path), buffer: Vec::new() };
+        let shared = Arc::new(Shared { capture: Mutex::new(Some(capture)), error: OnceLock::new() });
+
+        // holds the capture only while flushing, the last flush is left to `drop`
+        let flushed = Arc::downgrade(&shared);
+        thread::spawn(move || loop {
+            thread::sleep(FLUSH_INTERVAL);
+            match flushed.upgrade() {
+                Some(shared) => shared.with_capture(Capture::flush),
+                None => return,
+            }
+        });
+        Ok(Self { shared })
+    }
+
+    /// Writes `packet` stamped with the receive time, packets without a `ts` take the receive time
+    /// so the capture can be replayed
+    pub fn record(&self, packet: &AdsbPacket) {
+        let received = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let stamped;
+        let packet = if packet.ts.is_some() {
+            packet
+        } else {
+            stamped = AdsbPacket { ts: Some(received), ..packet.clone() };
+            &stamped
+        };
+
+        self.shared.with_capture(|capture| {
+            serde_json::to_writer(&mut capture.buffer, &Record { packet, received })?;
+            capture.buffer.push(b'\n');
+            Ok(())
+        });
+    }
+
+    /// Writes out everything recorded so far
+    pub fn flush(&self) {
+        self.shared.with_capture(Capture::flush);
+    }
+
+    /// Why recording stopped, if it failed
+    pub fn error(&self) -> Option<&str> {
+        self.shared.error.get().map(String::as_str)
+    }
+}
+
+impl Shared {
+    fn with_capture(&self, f: impl FnOnce(&mut Capture) -> io::Result<()>) {
+        let mut capture = self.capture.lock().unwrap();
+        let Some(active) = capture.as_mut() else {
+            return;
+        };
+        if let Err(e) = f(active) {
+            let _ = self.error.set(e.to_string());
+            *capture = None;
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::replay::Recording;
+    use crate::input::{Decoder, InputFormat};
+    use crate::test_util::packet;
+    use crate::simulator::geo::{GeoPoint, Projection};
+    use std::io::BufReader;
+
+    #[test]
+    fn test_recording_replays() {
+        for name in ["capture.jsonl", "capture.jsonl.gz"] {
+            let path = std::env::temp_dir().join(format!("actsim-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            // two sessions appending to the same capture
+            Recorder::create(&path).unwrap().record(&AdsbPacket { ts: Some(5.0), ..packet("A") });
+            let recorder = Recorder::create(&path).unwrap();
+            recorder.record(&AdsbPacket { ts: Some(6.0), ..packet("B") });
+            recorder.record(&packet("C"));
+            drop(recorder);
+
+            let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+            let reader = BufReader::new(open_capture(&path).unwrap());
+            let recording = Recording::read(reader, &mut decoder, |_, _| {}).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(vec!["A", "B", "C"], recording.packets.iter().map(|p| p.id.as_str()).collect::<Vec<_>>());
+            assert!(recording.packets[2].ts.unwrap() > 1_700_000_000.0);
+        }
+    }
+    #[test]
+    fn test_killed_recording_replays() {
+        let path = std::env::temp_dir().join(format!("actsim-{}-killed.jsonl.gz", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ids = || {
+            let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+            let reader = BufReader::new(open_capture(&path).unwrap());
+            let recording = Recording::read(reader, &mut decoder, |_, _| {}).unwrap();
+            recording.packets.iter().map(|p| p.id.clone()).collect::<Vec<_>>()
+        };
+
+        // written out in a lull in traffic, while the recorder is still open
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(&AdsbPacket { ts: Some(5.0), ..packet("A") });
+        thread::sleep(FLUSH_INTERVAL * 2);
+        assert_eq!(vec!["A"], ids());
+        drop(recorder);
+
+        // killed with a member cut short on disk
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"{\"id\":\"C\"}\n").unwrap();
+        let member = encoder.finish().unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&member[..member.len() / 2]).unwrap();
+
+        assert_eq!(vec!["A"], ids());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use crate::input::Rejection;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Tab-separated log of rejected input lines, shared by all source threads
+pub struct RejectLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl RejectLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { writer: Mutex::new(BufWriter::new(file)) })
+    }
+
+    /// Appends `receiver`, the rejection and the offending line. Write failures are ignored,
+    /// the log is a diagnostic and must not stop ingest
+    pub fn log(&self, receiver: &str, line: &str, rejection: &Rejection) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{receiver}\t{rejection}\t{line}").and_then(|_| writer.flush());
+    }
+}
@@ -0,0 +1,335 @@
+use crate::input::source_metrics::ConnectionStats;
+use crate::input::record;
+use crate::input::{process_adsb_stream, Decoder, Rejection};
+use crate::simulator::model::AdsbPacket;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IDLE_SLEEP: Duration = Duration::from_millis(10);
+
+/// Parses a playback speed multiplier, `max` replays as fast as possible
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    if s.eq_ignore_ascii_case("max") {
+        return Ok(f64::INFINITY);
+    }
+    match s.trim_end_matches(['x', 'X']).parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!("invalid speed '{s}', expected a positive multiplier or 'max'")),
+    }
+}
+
+/// Timestamped packets of a capture, ordered by the time they were received
+pub struct Recording {
+    pub packets: Vec<AdsbPacket>,
+    /// Seconds from the start of the capture of every packet
+    offsets: Vec<f64>,
+}
+
+impl Recording {
+    /// Reads a capture, packets without a timestamp can't be placed on the timeline and are skipped.
+    /// Lines that fail to decode are handed to `reject`, a truncated tail ends the capture.
+    ///
+    /// Captures written by `--record` are played back in the wall-clock order they were received in.
+    /// Otherwise receivers' timestamps may be on unrelated time bases, every receiver's are taken
+    /// relative to its earliest, as if they all started together
+    pub fn read<R, E>(reader: R, decoder: &mut Decoder, reject: E) -> io::Result<Self>
+    where
+        R: BufRead,
+        E: FnMut(&str, Rejection),
+    {
+        let mut timed = Vec::new();
+        let result = process_adsb_stream(reader, decoder, |packet| {
+            if packet.ts.is_some_and(f64::is_finite) {
+                timed.push((record::received(&packet), packet));
+            }
+        }, reject);
+        match result {
+            // a recording that was killed mid-write ends in a truncated gzip member
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !timed.is_empty() => {}
+            result => result?,
+        }
+
+        if timed.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "capture contains no timestamped packets"));
+        }
+
+        let mut timeline: Vec<(f64, AdsbPacket)> = if timed.iter().all(|(received, _)| received.is_some()) {
+            timed.into_iter().map(|(received, packet)| (received.unwrap_or_default(), packet)).collect()
+        } else {
+            let mut first: HashMap<Option<Arc<str>>, f64> = HashMap::new();
+            for (_, packet) in &timed {
+                let ts = packet.ts.unwrap_or_default();
+                first.entry(packet.receiver.clone()).and_modify(|first| *first = first.min(ts)).or_insert(ts);
+            }
+            timed.into_iter().map(|(_, packet)| (packet.ts.unwrap_or_default() - first[&packet.receiver], packet)).collect()
+        };
+        timeline.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let start = timeline[0].0;
+        let (offsets, packets) = timeline.into_iter().map(|(time, packet)| (time - start, packet)).unzip();
+        Ok(Self { packets, offsets })
+    }
+
+    /// Offset of the packet at `idx` from the start of the capture, in seconds
+    fn offset(&self, idx: usize) -> f64 {
+        self.offsets[idx]
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.offset(self.packets.len() - 1)
+    }
+
+    fn index_at(&self, offset: f64) -> usize {
+        self.offsets.partition_point(|&o| o < offset)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ReplayStatus {
+    pub paused: bool,
+    /// Playback speed multiplier, infinite replays as fast as possible
+    pub speed: f64,
+    /// Current offset into the capture in seconds
+    pub position: f64,
+    pub duration: f64,
+}
+
+struct ReplayState {
+    paused: bool,
+    speed: f64,
+    position: f64,
+    duration: f64,
+    seek: Option<f64>,
+    /// Bumped on every rewind, the consumer must discard its state before playback continues
+    generation: u64,
+    acknowledged: u64,
+}
+
+/// Playback controls shared between the replay thread and the TUI
+pub struct ReplayControl {
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayControl {
+    pub fn new(speed: f64, duration: f64) -> Self {
+        Self {
+            state: Mutex::new(ReplayState {
+                paused: false,
+                speed,
+                position: 0.0,
+                duration,
+                seek: None,
+                generation: 0,
+                acknowledged: 0,
+            }),
+        }
+    }
+
+    pub fn toggle_pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = !state.paused;
+    }
+
+    /// Scales playback speed by `factor`, as-fast-as-possible playback drops back to real time
+    pub fn scale_speed(&self, factor: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.speed = if state.speed.is_infinite() { 1.0 } else { (state.speed * factor).clamp(0.125, 1024.0) };
+    }
+
+    /// Moves playback by `delta` seconds, clamped to the capture
+    pub fn seek_by(&self, delta: f64) {
+        let mut state = self.state.lock().unwrap();
+        let target = (state.seek.unwrap_or(state.position) + delta).clamp(0.0, state.duration);
+        state.seek = Some(target);
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        let state = self.state.lock().unwrap();
+        ReplayStatus {
+            paused: state.paused,
+            speed: state.speed,
+            position: state.position,
+            duration: state.duration,
+        }
+    }
+
+    /// Returns true once per rewind, the caller resets its state and playback resumes
+    pub fn take_rewind(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let rewound = state.acknowledged != state.generation;
+        state.acknowledged = state.generation;
+        rewound
+    }
+}
+
+/// Pushes the recording into `tx` paced by its timeline
+pub fn spawn(recording: Recording, control: Arc<ReplayControl>, tx: Sender<AdsbPacket>, stats: Arc<ConnectionStats>) {
+    thread::spawn(move || {
+        stats.connect();
+        let mut idx = 0;
+        let mut last_step = Instant::now();
+
+        loop {
+            let (paused, speed, position) = {
+                let mut state = control.state.lock().unwrap();
+                if let Some(target) = state.seek.take() {
+                    if target < state.position {
+                        state.generation += 1;
+                    }
+                    state.position = target;
+                    idx = recording.index_at(target);
+                }
+                if state.acknowledged != state.generation {
+                    // wait for the consumer to drop pre-rewind state
+                    drop(state);
+                    thread::sleep(IDLE_SLEEP);
+                    last_step = Instant::now();
+                    continue;
+                }
+
+                let elapsed = last_step.elapsed().as_secs_f64();
+                last_step = Instant::now();
+                if !state.paused {
+                    state.position = if state.speed.is_infinite() {
+                        recording.offset(idx.min(recording.packets.len() - 1))
+                    } else {
+                        (state.position + elapsed * state.speed).min(state.duration)
+                    };
+                }
+                (state.paused, state.speed, state.position)
+            };
+
+            if paused || idx >= recording.packets.len() {
+                thread::sleep(IDLE_SLEEP);
+                continue;
+            }
+
+            while idx < recording.packets.len() && recording.offset(idx) <= position {
+                let packet = &recording.packets[idx];
+                stats.packets.fetch_add(1, Ordering::Relaxed);
+                if tx.send(packet.clone()).is_err() {
+                    stats.disconnect();
+                    return;
+                }
+                idx += 1;
+            }
+
+            if !speed.is_infinite() && idx < recording.packets.len() {
+                let wait = (recording.offset(idx) - position) / speed;
+                thread::sleep(IDLE_SLEEP.min(Duration::from_secs_f64(wait.max(0.0))));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputFormat;
+    use crate::simulator::geo::{GeoPoint, Projection};
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+
+    fn recording(timestamps: &[f64]) -> Recording {
+        let capture: String = timestamps.iter().enumerate()
+            .map(|(i, ts)| format!(r#"{{"id":"P{i}","callsign":null,"px":0,"py":0,"vx":0,"vy":0,"alt":10000,"ts":{ts}}}"#) + "\n")
+            .collect();
+        let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+        Recording::read(Cursor::new(capture + "{\"id\":\"untimed\"}\n"), &mut decoder, |_, _| {}).unwrap()
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(Ok(1.0), parse_speed("1"));
+        assert_eq!(Ok(4.0), parse_speed("4x"));
+        assert_eq!(Ok(f64::INFINITY), parse_speed("max"));
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn test_recording_is_ordered() {
+        let recording = recording(&[100.0, 130.0, 110.0]);
+
+        assert_eq!(vec!["P0", "P2", "P1"], recording.packets.iter().map(|p| p.id.as_str()).collect::<Vec<_>>());
+        assert_eq!(30.0, recording.duration());
+        assert_eq!(1, recording.index_at(5.0));
+    }
+
+    #[test]
+    fn test_receivers_on_their_own_time_base() {
+        let line = |id: &str, receiver: &str, ts: f64, received: Option<f64>| {
+            let received = received.map(|r| format!(r#","received":{r}"#)).unwrap_or_default();
+            format!(r#"{{"id":"{id}","callsign":null,"px":0,"py":0,"vx":0,"vy":0,"alt":10000,"ts":{ts},"receiver":"{receiver}"{received}}}"#) + "\n"
+        };
+        let read = |capture: String| {
+            let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+            let recording = Recording::read(Cursor::new(capture), &mut decoder, |_, _| {}).unwrap();
+            let ids: Vec<String> = recording.packets.iter().map(|p| p.id.clone()).collect();
+            (ids, recording.duration())
+        };
+
+        // unix seconds next to a Beast MLAT counter
+        let capture = line("S0", "sbs", 1_700_000_000.0, None) + &line("B0", "beast", 50.0, None)
+            + &line("S1", "sbs", 1_700_000_002.0, None) + &line("B1", "beast", 51.0, None);
+        assert_eq!((vec!["S0".to_string(), "B0".to_string(), "B1".to_string(), "S1".to_string()], 2.0), read(capture));
+
+        // recorded captures keep the order they were received in
+        let capture = line("S0", "sbs", 1_700_000_000.0, Some(1000.0)) + &line("B0", "beast", 50.0, Some(1000.5))
+            + &line("S1", "sbs", 1_700_000_002.0, Some(1003.0)) + &line("B1", "beast", 51.0, Some(1001.5));
+        assert_eq!((vec!["S0".to_string(), "B0".to_string(), "B1".to_string(), "S1".to_string()], 3.0), read(capture));
+    }
+
+    #[test]
+    fn test_reports_rejected_lines() {
+        let capture = r#"{"id":"P0","callsign":null,"px":0,"py":0,"vx":0,"vy":0,"alt":10000,"ts":1}"#.to_string() + "\nnot json\n";
+        let mut decoder = Decoder::new(InputFormat::Json, Projection::new(GeoPoint::new(0.0, 0.0)));
+        let mut rejected = Vec::new();
+        let recording = Recording::read(Cursor::new(capture), &mut decoder, |line, _| rejected.push(line.to_string())).unwrap();
+
+        assert_eq!(1, recording.packets.len());
+        assert_eq!(vec!["not json"], rejected);
+    }
+
+    #[test]
+    fn test_replay_paced_by_timestamps() {
+        let (tx, rx) = channel();
+        let recording = recording(&[0.0, 0.5, 1.0]);
+        let control = Arc::new(ReplayControl::new(100.0, recording.duration()));
+        spawn(recording, control.clone(), tx, Arc::new(ConnectionStats::new("replay".to_string())));
+
+        let start = Instant::now();
+        let ids: Vec<String> = (0..3).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap().id).collect();
+
+        assert_eq!(vec!["P0", "P1", "P2"], ids);
+        // 1s of capture at 100x
+        assert!(start.elapsed() >= Duration::from_millis(9), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_rewind_waits_for_consumer() {
+        let (tx, rx) = channel();
+        let recording = recording(&[0.0, 10.0, 20.0]);
+        let control = Arc::new(ReplayControl::new(f64::INFINITY, recording.duration()));
+        spawn(recording, control.clone(), tx, Arc::new(ConnectionStats::new("replay".to_string())));
+
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        control.seek_by(-15.0);
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        assert!(control.take_rewind());
+        assert!(!control.take_rewind());
+        assert_eq!("P1", rx.recv_timeout(Duration::from_secs(5)).unwrap().id);
+        assert_eq!("P2", rx.recv_timeout(Duration::from_secs(5)).unwrap().id);
+    }
+}
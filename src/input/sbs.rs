@@ -0,0 +1,251 @@
+use crate::simulator::geo::{GeoPoint, Projection, FPM_TO_MS};
+use crate::simulator::math::Vector2D;
+use crate::simulator::model::AdsbPacket;
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Tracks silent for longer than this lose their state
+const TRACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FIELD_HEX_IDENT: usize = 4;
+const FIELD_DATE_GENERATED: usize = 6;
+const FIELD_TIME_GENERATED: usize = 7;
+const FIELD_CALLSIGN: usize = 10;
+const FIELD_ALTITUDE: usize = 11;
+const FIELD_GROUND_SPEED: usize = 12;
+const FIELD_TRACK: usize = 13;
+const FIELD_LAT: usize = 14;
+const FIELD_LON: usize = 15;
+const FIELD_VERTICAL_RATE: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum SbsError {
+    NotAMessage,
+    MissingField(usize),
+    InvalidField(usize, String),
+}
+
+impl fmt::Display for SbsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbsError::NotAMessage => write!(f, "not a MSG row"),
+            SbsError::MissingField(idx) => write!(f, "missing field {idx}"),
+            SbsError::InvalidField(idx, value) => write!(f, "invalid value '{value}' in field {idx}"),
+        }
+    }
+}
+
+impl std::error::Error for SbsError {}
+
+struct Row<'a> {
+    fields: Vec<&'a str>,
+}
+
+impl<'a> Row<'a> {
+    fn parse(line: &'a str) -> Result<(u8, Self), SbsError> {
+        let fields: Vec<&str> = line.trim().split(',').map(|f| f.trim()).collect();
+        if fields.first() != Some(&"MSG") {
+            return Err(SbsError::NotAMessage);
+        }
+        let row = Self { fields };
+        let transmission_type = row.required(1)?;
+        Ok((transmission_type, row))
+    }
+
+    fn str(&self, idx: usize) -> Option<&'a str> {
+        self.fields.get(idx).copied().filter(|f| !f.is_empty())
+    }
+
+    fn optional<T: std::str::FromStr>(&self, idx: usize) -> Result<Option<T>, SbsError> {
+        self.str(idx)
+            .map(|f| f.parse::<T>().map_err(|_| SbsError::InvalidField(idx, f.to_string())))
+            .transpose()
+    }
+
+    fn required<T: std::str::FromStr>(&self, idx: usize) -> Result<T, SbsError> {
+        self.optional(idx)?.ok_or(SbsError::MissingField(idx))
+    }
+
+    /// Time the message was generated in unix seconds, read as UTC
+    fn timestamp(&self) -> Result<Option<f64>, SbsError> {
+        let (Some(date), Some(time)) = (self.str(FIELD_DATE_GENERATED), self.str(FIELD_TIME_GENERATED)) else {
+            return Ok(None);
+        };
+        NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y/%m/%d %H:%M:%S%.f")
+            .map(|t| Some(t.and_utc().timestamp_micros() as f64 / 1e6))
+            .map_err(|_| SbsError::InvalidField(FIELD_TIME_GENERATED, format!("{date} {time}")))
+    }
+}
+
+struct Track {
+    callsign: Option<String>,
+    position: Option<GeoPoint>,
+    altitude: Option<f64>,
+    velocity: Option<Vector2D>,
+    /// ft/min
+    vertical_rate: Option<f64>,
+    last_update: Instant,
+}
+
+/// Merges BaseStation `MSG,1..8` rows into complete packets per hex ident
+pub struct SbsDecoder {
+    projection: Projection,
+    tracks: HashMap<String, Track>,
+    last_sweep: Instant,
+}
+
+impl SbsDecoder {
+    pub fn new(projection: Projection) -> Self {
+        Self {
+            projection,
+            tracks: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Applies one row, emitting a packet when a position (MSG,2 and 3) or velocity (MSG,4) update
+    /// completes the track. The packet is stamped with the time the row was generated
+    pub fn decode_line(&mut self, line: &str) -> Result<Option<AdsbPacket>, SbsError> {
+        let (transmission_type, row) = Row::parse(line)?;
+        let ident = row.str(FIELD_HEX_IDENT)
+            .ok_or(SbsError::MissingField(FIELD_HEX_IDENT))?
+            .to_uppercase();
+        let ts = row.timestamp()?;
+
+        if self.last_sweep.elapsed() > TRACK_TIMEOUT {
+            self.tracks.retain(|_, t| t.last_update.elapsed() < TRACK_TIMEOUT);
+            self.last_sweep = Instant::now();
+        }
+
+        let track = self.tracks.entry(ident.clone()).or_insert_with(|| Track {
+            callsign: None,
+            position: None,
+            altitude: None,
+            velocity: None,
+            vertical_rate: None,
+            last_update: Instant::now(),
+        });
+        track.last_update = Instant::now();
+
+        match transmission_type {
+            1 => {
+                track.callsign = row.str(FIELD_CALLSIGN).map(|c| c.to_string());
+                return Ok(None);
+            }
+            2 | 3 => {
+                let altitude = row.optional::<f64>(FIELD_ALTITUDE)?;
+                let lat = row.required::<f64>(FIELD_LAT)?;
+                let lon = row.required::<f64>(FIELD_LON)?;
+                track.position = Some(GeoPoint::new(lat, lon));
+                if altitude.is_some() {
+                    track.altitude = altitude;
+                }
+                // surface positions carry ground speed and track too
+                if let (Some(speed), Some(heading)) = (row.optional::<f64>(FIELD_GROUND_SPEED)?, row.optional::<f64>(FIELD_TRACK)?) {
+                    track.velocity = Some(Projection::velocity(speed, heading));
+                }
+            }
+            4 => {
+                let speed = row.required::<f64>(FIELD_GROUND_SPEED)?;
+                let heading = row.required::<f64>(FIELD_TRACK)?;
+                track.velocity = Some(Projection::velocity(speed, heading));
+                track.vertical_rate = row.optional::<f64>(FIELD_VERTICAL_RATE)?;
+            }
+            _ => {
+                // MSG,5 and MSG,7 carry altitude only
+                if let Some(altitude) = row.optional::<f64>(FIELD_ALTITUDE)? {
+                    track.altitude = Some(altitude);
+                }
+                return Ok(None);
+            }
+        }
+
+        Ok(Self::to_packet(ident, track, &self.projection, ts))
+    }
+
+    fn to_packet(ident: String, track: &Track, projection: &Projection, ts: Option<f64>) -> Option<AdsbPacket> {
+        let (position, velocity, alt) = (track.position?, track.velocity?, track.altitude?);
+        let p = projection.project(position);
+
+        Some(AdsbPacket {
+            id: ident,
+            callsign: track.callsign.clone(),
+            px: p.x,
+            py: p.y,
+            vx: velocity.x,
+            vy: velocity.y,
+            vz: track.vertical_rate.unwrap_or(0.0) * FPM_TO_MS,
+            alt,
+            ts,
+            signal: None,
+            receiver: None,
+            trusted: false,
+            raw: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::geo::KNOTS_TO_MS;
+
+    #[test]
+    fn test_merges_rows() {
+        let mut decoder = SbsDecoder::new(Projection::new(GeoPoint::new(52.2572, 3.9194)));
+
+        let callsign = "MSG,1,1,1,4840D6,1,2016/03/14,12:00:00.000,2016/03/14,12:00:00.000,KLM1023 ,,,,,,,,,,,0";
+        let position = "MSG,3,1,1,4840D6,1,2016/03/14,12:00:00.000,2016/03/14,12:00:00.000,,38000,,,52.2572,3.9194,,,0,0,0,0";
+        let velocity = "MSG,4,1,1,4840d6,1,2016/03/14,12:00:00.000,2016/03/14,12:00:00.000,,,159,180,,,-832,,,,,0";
+
+        assert_eq!(Ok(None), decoder.decode_line(callsign).map(|p| p.map(|p| p.id)));
+        assert_eq!(Ok(None), decoder.decode_line(position).map(|p| p.map(|p| p.id)));
+
+        let packet = decoder.decode_line(velocity).unwrap().unwrap();
+        assert_eq!("4840D6", packet.id);
+        assert_eq!(Some("KLM1023".to_string()), packet.callsign);
+        assert_eq!(38000.0, packet.alt);
+        assert!(packet.px.abs() < 1.0 && packet.py.abs() < 1.0, "({}, {})", packet.px, packet.py);
+        assert!(packet.vx.abs() < 1e-6, "vx {}", packet.vx);
+        assert!((packet.vy + 159.0 * KNOTS_TO_MS).abs() < 1e-6, "vy {}", packet.vy);
+        assert!((packet.vz + 832.0 * FPM_TO_MS).abs() < 1e-6, "vz {}", packet.vz);
+
+        assert_eq!(Some(1_457_956_800.0), packet.ts);
+
+        // subsequent position updates are emitted straight away
+        assert!(decoder.decode_line(position).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_surface_position() {
+        let mut decoder = SbsDecoder::new(Projection::new(GeoPoint::new(52.2572, 3.9194)));
+
+        // MSG,2 has no airborne altitude field but carries position, ground speed and track
+        let altitude = "MSG,5,1,1,4840D6,1,2016/03/14,12:00:00.000,2016/03/14,12:00:00.000,,0,,,,,,,0,,0,0";
+        let surface = "MSG,2,1,1,4840D6,1,2016/03/14,12:00:01.500,2016/03/14,12:00:01.500,,,12,90,52.2572,3.9194,,,,,,0";
+        assert_eq!(Ok(None), decoder.decode_line(altitude).map(|p| p.map(|p| p.id)));
+
+        let packet = decoder.decode_line(surface).unwrap().unwrap();
+        assert!(packet.px.abs() < 1.0 && packet.py.abs() < 1.0, "({}, {})", packet.px, packet.py);
+        assert!((packet.vx - 12.0 * KNOTS_TO_MS).abs() < 1e-6, "vx {}", packet.vx);
+        assert_eq!(Some(1_457_956_801.5), packet.ts);
+    }
+
+    #[test]
+    fn test_rejects_malformed_rows() {
+        let mut decoder = SbsDecoder::new(Projection::new(GeoPoint::new(0.0, 0.0)));
+
+        assert_eq!(Err(SbsError::NotAMessage), decoder.decode_line("STA,,5,179,400AE7,10103,2008/11/28,14:58:51.153,,,RM").map(|_| ()));
+        assert_eq!(Err(SbsError::MissingField(14)), decoder.decode_line("MSG,3,1,1,4840D6,1,,,,,,38000,,,,,,,,,,").map(|_| ()));
+        assert_eq!(
+            Err(SbsError::InvalidField(12, "fast".to_string())),
+            decoder.decode_line("MSG,4,1,1,4840D6,1,,,,,,,fast,180,,,,,,,,").map(|_| ())
+        );
+        assert_eq!(
+            Err(SbsError::InvalidField(7, "2016/03/14 noon".to_string())),
+            decoder.decode_line("MSG,4,1,1,4840D6,1,2016/03/14,noon,,,,,159,180,,,,,,,,").map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use crate::filter::membership::SharedFilter;
+use crate::input::record;
+use crate::input::record::Recorder;
+use crate::input::reject_log::RejectLog;
+use crate::input::source_metrics::{ConnectionStats, SourceMetrics};
+use crate::input::{process_adsb_stream, Decoder, InputFormat};
+use crate::simulator::geo::Projection;
+use crate::simulator::model::AdsbPacket;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufReader, Cursor, Read};
+use std::net::{IpAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A feeder silent for this long is taken for a dead connection and reconnected
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+    /// Connects to a feeder, reconnecting with backoff when the connection drops
+    TcpClient(String),
+    /// Accepts feeders pushing data to us
+    TcpListener(String),
+    Udp(String),
+}
+
+impl Source {
+    /// Accepts `:port` as shorthand for all interfaces
+    fn bind_addr(addr: &str) -> String {
+        match addr.strip_prefix(':') {
+            Some(port) => format!("0.0.0.0:{port}"),
+            None => addr.to_string(),
+        }
+    }
+
+    pub fn listen(addr: &str) -> Source {
+        Source::TcpListener(Self::bind_addr(addr))
+    }
+
+    pub fn udp(addr: &str) -> Source {
+        Source::Udp(Self::bind_addr(addr))
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Source::Stdin => "stdin".to_string(),
+            Source::File(path) => path.display().to_string(),
+            Source::TcpClient(addr) => format!("tcp://{addr}"),
+            Source::TcpListener(addr) => addr.clone(),
+            Source::Udp(addr) => format!("udp://{addr}"),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(Source::Stdin);
+        }
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return match addr {
+                "" => Err(format!("missing address in '{s}'")),
+                addr => Ok(Source::TcpClient(addr.to_string())),
+            };
+        }
+        let path = s.strip_prefix("file://").unwrap_or(s);
+        if path.is_empty() || path.contains("://") {
+            return Err(format!("unsupported source '{s}', expected tcp://host:port, a file path or -"));
+        }
+        Ok(Source::File(PathBuf::from(path)))
+    }
+}
+
+/// A source tagged with the id of the receiver it carries data from, written as `[ID=]SOURCE`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceSpec {
+    pub receiver: Arc<str>,
+    pub source: Source,
+}
+
+impl SourceSpec {
+    fn parse_with(s: &str, parse: impl Fn(&str) -> Result<Source, String>) -> Result<Self, String> {
+        let (id, rest) = match s.split_once('=') {
+            Some((id, rest)) if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) => (Some(id), rest),
+            _ => (None, s),
+        };
+        let source = parse(rest)?;
+        Ok(Self {
+            receiver: Arc::from(id.map(|id| id.to_string()).unwrap_or_else(|| source.name())),
+            source,
+        })
+    }
+
+    pub fn parse_listen(s: &str) -> Result<Self, String> {
+        Self::parse_with(s, |addr| Ok(Source::listen(addr)))
+    }
+
+    pub fn parse_udp(s: &str) -> Result<Self, String> {
+        Self::parse_with(s, |addr| Ok(Source::udp(addr)))
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(s, Source::from_str)
+    }
+}
+
+/// Trusted ID lookup run on the source threads, keyed like the consumer's filter
+#[derive(Clone)]
+pub struct TrustedCheck {
+    pub filter: Arc<dyn SharedFilter>,
+    pub key: fn(&AdsbPacket) -> &str,
+}
+
+/// Everything a source thread needs to decode its stream into the shared packet channel
+#[derive(Clone)]
+pub struct Ingest {
+    pub format: InputFormat,
+    pub projection: Projection,
+    pub tx: Sender<AdsbPacket>,
+    pub metrics: Arc<SourceMetrics>,
+    pub reject_log: Option<Arc<RejectLog>>,
+    /// Captures every decoded packet, before duplicates across receivers are dropped
+    pub recorder: Option<Arc<Recorder>>,
+    /// Spares the consumer the filter lookup of trusted aircraft, which are most of the traffic
+    pub trusted: Option<TrustedCheck>,
+}
+
+struct CountingReader<R> {
+    inner: R,
+    stats: Arc<ConnectionStats>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.stats.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl Ingest {
+    /// Decodes `reader` until it is exhausted, tagging packets with `receiver`,
+    /// returns false once the consumer has gone away
+    fn consume<R: Read>(&self, reader: R, decoder: &mut Decoder, receiver: &Arc<str>, stats: &Arc<ConnectionStats>) -> io::Result<bool> {
+        let mut alive = true;
+        let reader = BufReader::new(CountingReader { inner: reader, stats: stats.clone() });
+
+        process_adsb_stream(reader, decoder, |mut packet| {
+            stats.packets.fetch_add(1, Ordering::Relaxed);
+            packet.receiver.get_or_insert_with(|| receiver.clone());
+            if let Some(recorder) = &self.recorder {
+                recorder.record(&packet);
+            }
+            if let Some(check) = &self.trusted {
+                packet.trusted = check.filter.check_trusted((check.key)(&packet));
+            }
+            alive = alive && self.tx.send(packet).is_ok();
+        }, |line, rejection| {
+            self.metrics.count_rejection(&rejection);
+            if let Some(log) = &self.reject_log {
+                log.log(receiver, line, &rejection);
+            }
+        })?;
+
+        Ok(alive)
+    }
+
+    /// Reads a stream that is consumed once, like stdin or a file
+    fn spawn_reader<R: Read + Send + 'static>(&self, reader: R, receiver: Arc<str>) {
+        let ingest = self.clone();
+        thread::spawn(move || {
+            let stats = ingest.metrics.register(receiver.to_string());
+            stats.connect();
+            let mut decoder = Decoder::new(ingest.format, ingest.projection);
+            if ingest.consume(reader, &mut decoder, &receiver, &stats).is_err() {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            stats.disconnect();
+        });
+    }
+
+    pub fn spawn(&self, spec: SourceSpec) -> io::Result<()> {
+        let receiver = spec.receiver;
+        match spec.source {
+            Source::Stdin => self.spawn_reader(io::stdin(), receiver),
+            Source::File(path) => self.spawn_reader(record::open_capture(&path)?, receiver),
+            Source::TcpClient(addr) => {
+                let ingest = self.clone();
+                thread::spawn(move || ingest.run_tcp_client(addr, receiver));
+            }
+            Source::TcpListener(addr) => self.spawn_listener(TcpListener::bind(addr)?, receiver),
+            Source::Udp(addr) => self.spawn_udp(UdpSocket::bind(addr)?, receiver),
+        }
+        Ok(())
+    }
+
+    fn run_tcp_client(&self, addr: String, receiver: Arc<str>) {
+        let stats = self.metrics.register(receiver.to_string());
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            match TcpStream::connect(&addr).and_then(|stream| stream.set_read_timeout(Some(READ_TIMEOUT)).map(|_| stream)) {
+                Ok(stream) => {
+                    backoff = INITIAL_BACKOFF;
+                    stats.connect();
+
+                    // decoder state doesn't survive a reconnect, frames may have been lost. A read timing out
+                    // ends the connection with an error like a reset would
+                    let mut decoder = Decoder::new(self.format, self.projection);
+                    let result = self.consume(stream, &mut decoder, &receiver, &stats);
+                    stats.disconnect();
+                    match result {
+                        Ok(false) => return,
+                        Ok(true) => {}
+                        Err(_) => { stats.errors.fetch_add(1, Ordering::Relaxed); }
+                    }
+                }
+                Err(_) => {
+                    stats.errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Every feeder host connecting to the listener is a receiver of its own, `<listener id>/<peer ip>`, so a
+    /// feeder reconnecting from a new port keeps its stats and time base
+    fn spawn_listener(&self, listener: TcpListener, receiver: Arc<str>) {
+        let ingest = self.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let peer = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+                let receiver: Arc<str> = Arc::from(format!("{receiver}/{peer}"));
+                let stats = ingest.metrics.register(receiver.to_string());
+                let ingest = ingest.clone();
+
+                thread::spawn(move || {
+                    stats.connect();
+                    let mut decoder = Decoder::new(ingest.format, ingest.projection);
+                    if ingest.consume(stream, &mut decoder, &receiver, &stats).is_err() {
+                        stats.errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    stats.disconnect();
+                });
+            }
+        });
+    }
+
+    /// Datagrams are tagged by sending host like listener connections, each sender keeps its own decoder until
+    /// it has been silent for `READ_TIMEOUT`
+    fn spawn_udp(&self, socket: UdpSocket, receiver: Arc<str>) {
+        let ingest = self.clone();
+
+        thread::spawn(move || {
+            let stats = ingest.metrics.register(receiver.to_string());
+            stats.connect();
+
+            let mut peers: HashMap<IpAddr, (Arc<str>, Decoder, Instant)> = HashMap::new();
+            let mut last_sweep = Instant::now();
+            let mut buf = vec![0u8; 65_536];
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let (n, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => {
+                        stats.errors.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                };
+                backoff = INITIAL_BACKOFF;
+
+                let now = Instant::now();
+                if now.duration_since(last_sweep) > READ_TIMEOUT {
+                    peers.retain(|_, (.., seen)| now.duration_since(*seen) < READ_TIMEOUT);
+                    last_sweep = now;
+                }
+                let (peer_receiver, decoder, seen) = peers.entry(peer.ip()).or_insert_with(|| (
+                    Arc::from(format!("{receiver}/{}", peer.ip())),
+                    Decoder::new(ingest.format, ingest.projection),
+                    now,
+                ));
+                *seen = now;
+                match ingest.consume(Cursor::new(&buf[..n]), decoder, peer_receiver, &stats) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(_) => { stats.errors.fetch_add(1, Ordering::Relaxed); }
+                }
+            }
+            stats.disconnect();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::filter::concurrent_bloom_filter::ConcurrentBloomFilter;
+    use crate::filter::config::FilterConfig;
+    use crate::filter::membership::MembershipFilter;
+    use crate::simulator::geo::GeoPoint;
+    use std::io::Write;
+    use std::sync::mpsc::{channel, Receiver};
+
+    const PACKET: &str = r#"{"id":"ABC123","callsign":null,"px":1.0,"py":2.0,"vx":3.0,"vy":4.0,"alt":10000}"#;
+
+    fn ingest() -> (Ingest, Receiver<AdsbPacket>) {
+        let (tx, rx) = channel();
+        let ingest = Ingest {
+            format: InputFormat::Json,
+            projection: Projection::new(GeoPoint::new(0.0, 0.0)),
+            tx,
+            metrics: Arc::new(SourceMetrics::new()),
+            reject_log: None,
+            recorder: None,
+            trusted: None,
+        };
+        (ingest, rx)
+    }
+
+    fn spec(receiver: &str, source: Source) -> SourceSpec {
+        SourceSpec { receiver: Arc::from(receiver), source }
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(Ok(Source::TcpClient("localhost:30005".to_string())), "tcp://localhost:30005".parse());
+        assert_eq!(Ok(Source::Stdin), "-".parse());
+        assert_eq!(Ok(Source::File(PathBuf::from("capture.jsonl"))), "file://capture.jsonl".parse());
+        assert!("udp://localhost:30005".parse::<Source>().is_err());
+        assert_eq!(Source::Udp("0.0.0.0:30003".to_string()), Source::udp(":30003"));
+        assert_eq!(Source::TcpListener("127.0.0.1:30005".to_string()), Source::listen("127.0.0.1:30005"));
+    }
+
+    #[test]
+    fn test_parse_source_spec() {
+        assert_eq!(
+            Ok(spec("rx-1", Source::TcpClient("localhost:30005".to_string()))),
+            "rx-1=tcp://localhost:30005".parse()
+        );
+        assert_eq!(Ok(spec("stdin", Source::Stdin)), "-".parse());
+        assert_eq!(Ok(spec("data/a=b.jsonl", Source::File(PathBuf::from("data/a=b.jsonl")))), "data/a=b.jsonl".parse());
+        assert_eq!(Ok(spec("roof", Source::Udp("0.0.0.0:30003".to_string()))), SourceSpec::parse_udp("roof=:30003"));
+    }
+
+    #[test]
+    fn test_trusted_check() {
+        let mut filter: ConcurrentBloomFilter = ConcurrentBloomFilter::new(&FilterConfig::default(), Clock::new());
+        filter.insert(&"ABC123");
+        let (ingest, rx) = ingest();
+        let ingest = Ingest { trusted: Some(TrustedCheck { filter: Arc::new(filter), key: |packet| &packet.id }), ..ingest };
+
+        let input = format!("{PACKET}\n{}\n", PACKET.replace("ABC123", "DEF456"));
+        let receiver = Arc::from("rx1");
+        let stats = ingest.metrics.register("rx1".to_string());
+        let mut decoder = Decoder::new(ingest.format, ingest.projection);
+        ingest.consume(input.as_bytes(), &mut decoder, &receiver, &stats).unwrap();
+
+        assert!(rx.recv().unwrap().trusted);
+        assert!(!rx.recv().unwrap().trusted);
+    }
+
+    #[test]
+    fn test_tcp_client_reconnects() {
+        let (ingest, rx) = ingest();
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        ingest.spawn(spec("rx1", Source::TcpClient(addr))).unwrap();
+
+        for _ in 0..2 {
+            let (mut conn, _) = server.accept().unwrap();
+            writeln!(conn, "{PACKET}").unwrap();
+            writeln!(conn, "not a packet").unwrap();
+            let packet = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!("ABC123", packet.id);
+            assert_eq!(Some(Arc::from("rx1")), packet.receiver);
+        }
+
+        let stats = ingest.metrics.register("rx1".to_string());
+        assert_eq!(2, stats.connects.load(Ordering::Relaxed));
+        assert_eq!(2, stats.packets.load(Ordering::Relaxed));
+        assert!(stats.bytes.load(Ordering::Relaxed) > 2 * PACKET.len() as u64);
+    }
+
+    #[test]
+    fn test_tcp_listener() {
+        let (ingest, rx) = ingest();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        ingest.spawn_listener(listener, Arc::from("feeders"));
+
+        // a feeder reconnecting from another port while its old connection lingers
+        let mut feeders: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for feeder in &mut feeders {
+            writeln!(feeder, "{PACKET}").unwrap();
+        }
+        for _ in 0..2 {
+            let packet = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(Some(Arc::from("feeders/127.0.0.1")), packet.receiver);
+        }
+
+        let connections = ingest.metrics.connections();
+        assert_eq!(1, connections.len());
+        assert_eq!(2, connections[0].connects.load(Ordering::Relaxed));
+        assert_eq!(2, connections[0].packets.load(Ordering::Relaxed));
+
+        // the old connection dropping leaves the feeder connected
+        drop(feeders.remove(0));
+        writeln!(feeders[0], "{PACKET}").unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(connections[0].connected());
+        drop(feeders);
+        for _ in 0..500 {
+            if !connections[0].connected() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!connections[0].connected());
+    }
+
+    #[test]
+    fn test_udp() {
+        let (ingest, rx) = ingest();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        ingest.spawn_udp(socket, Arc::from("udp"));
+
+        // senders on the same host are one receiver
+        for _ in 0..2 {
+            let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sender.send_to(format!("{PACKET}\n").as_bytes(), addr).unwrap();
+        }
+
+        for _ in 0..2 {
+            let packet = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!("ABC123", packet.id);
+            assert_eq!(Some(Arc::from("udp/127.0.0.1")), packet.receiver);
+        }
+        assert_eq!(2, ingest.metrics.connections()[0].packets.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::input::Rejection;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub struct ConnectionStats {
+    pub name: String,
+    /// Connections open at once, feeders behind one address share their stats
+    open: AtomicUsize,
+    pub connects: AtomicU64,
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn new(name: String) -> ConnectionStats {
+        ConnectionStats {
+            name,
+            open: AtomicUsize::new(0),
+            connects: AtomicU64::new(0),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connect(&self) {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        self.connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnect(&self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) -> bool {
+        self.open.load(Ordering::Relaxed) > 0
+    }
+}
+
+pub struct SourceMetrics {
+    connections: Mutex<Vec<Arc<ConnectionStats>>>,
+    /// Reports dropped because another receiver already delivered them
+    pub duplicates: AtomicU64,
+    /// Lines that couldn't be decoded
+    pub malformed: AtomicU64,
+    /// Lines that decoded to physically impossible packets
+    pub implausible: AtomicU64,
+}
+
+impl SourceMetrics {
+    pub fn new() -> SourceMetrics {
+        SourceMetrics {
+            connections: Mutex::new(Vec::new()),
+            duplicates: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            implausible: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the stats registered under `name`, so a reconnecting peer keeps its counters
+    pub fn register(&self, name: String) -> Arc<ConnectionStats> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(existing) = connections.iter().find(|c| c.name == name) {
+            return existing.clone();
+        }
+        let stats = Arc::new(ConnectionStats::new(name));
+        connections.push(stats.clone());
+        stats
+    }
+
+    pub fn count_rejection(&self, rejection: &Rejection) {
+        let counter = match rejection {
+            Rejection::Malformed(_) => &self.malformed,
+            Rejection::Implausible(_) => &self.implausible,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> Vec<Arc<ConnectionStats>> {
+        self.connections.lock().unwrap().clone()
+    }
+}
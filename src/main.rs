@@ -1,17 +1,39 @@
-use crate::filter::filter_manager;
-use crate::filter::filter_manager::FilterResult;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::filter::bloom_filter::ScalableBloomFilter;
+use crate::filter::concurrent_bloom_filter::ConcurrentBloomFilter;
+use crate::filter::config::FilterBackend;
+use crate::filter::cuckoo_filter::CuckooFilter;
+use crate::filter::filter_manager::{FilterManager, FilterResult, Pending};
+use crate::filter::membership::MembershipFilter;
+use crate::filter::output::{FilterOutput, FilterRecord, OutputFormat};
+use crate::input::record::Recorder;
+use crate::input::reject_log::RejectLog;
+use crate::input::replay::{Recording, ReplayControl};
+use crate::input::{merge, record, replay, Decoder};
+use crate::input::source::{Ingest, SourceSpec, TrustedCheck};
+use crate::input::source_metrics::SourceMetrics;
+use crate::input::InputFormat;
+use crate::simulator::config::RiskModel;
+use crate::simulator::geo::{GeoPoint, Projection};
 use crate::simulator::model::AdsbPacket;
-use crate::tui::sim_app::SimApp;
+use crate::tui::sim_app::{track_name, SimApp};
 use clap::{Parser, ValueEnum};
-use std::{io, thread};
-use std::io::BufRead;
-use std::time::{Duration, Instant};
+use std::hash::Hash;
+use std::io;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug, ValueEnum)]
 #[value(rename_all = "lowercase")]
 enum Command {
     Filter,
     Simulate,
+    Replay,
 }
 
 #[derive(Parser, Debug)]
@@ -21,6 +43,21 @@ struct Args {
     )]
     command: Command,
 
+    /// Capture to replay, packets must carry a ts
+    #[arg(
+        value_name = "FILE",
+        required_if_eq("command", "replay"),
+    )]
+    file: Option<PathBuf>,
+
+    /// Replay speed multiplier, or max to replay as fast as possible
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = replay::parse_speed,
+    )]
+    speed: f64,
+
     /// Max age in seconds for a filter before pruning
     #[arg(
         long,
@@ -28,75 +65,462 @@ struct Args {
     )]
     max_age: u64,
 
+    /// TOML config file, flags below override its values
+    #[arg(
+        long,
+        value_name = "PATH",
+    )]
+    config: Option<PathBuf>,
+
+    /// Membership filter holding promoted aircraft
+    #[arg(
+        long,
+        value_enum,
+    )]
+    filter_backend: Option<FilterBackend>,
+
+    /// Sightings before an aircraft is promoted into the filter
+    #[arg(
+        long,
+    )]
+    filter_threshold: Option<u8>,
+
+    /// Seconds in which the promotion sightings must fall
+    #[arg(
+        long,
+    )]
+    promotion_window: Option<f64>,
+
+    /// False positive rate of the first filter layer
+    #[arg(
+        long,
+    )]
+    target_fpr: Option<f64>,
+
+    /// Bits per hash partition of the filter
+    #[arg(
+        long,
+    )]
+    partition_size: Option<usize>,
+
+    /// Size multiplier of filter layers added once the first fills up
+    #[arg(
+        long,
+    )]
+    growth_factor: Option<usize>,
+
+    /// Factor applied to the target FPR of every added filter layer
+    #[arg(
+        long,
+    )]
+    tightening_ratio: Option<f64>,
+
+    /// Never inserted keys probed every prune to report the observed filter FPR
+    #[arg(
+        long,
+    )]
+    fpr_probes: Option<usize>,
+
+    /// Radius in metres of the protected volume around every aircraft
+    #[arg(
+        long,
+    )]
+    horizontal_radius: Option<f64>,
+
+    /// Half height in feet of the protected volume around every aircraft
+    #[arg(
+        long,
+    )]
+    vertical_band: Option<f64>,
+
+    /// Seconds conflicts between aircraft are predicted ahead
+    #[arg(
+        long,
+    )]
+    lookahead: Option<f64>,
+
+    /// Fastest expected ground speed in m/s, bounds how far apart aircraft can still conflict
+    #[arg(
+        long,
+    )]
+    max_speed: Option<f64>,
+
+    /// Bound of the velocity error in m/s the risk models assume
+    #[arg(
+        long,
+    )]
+    noise_magnitude: Option<f64>,
+
+    /// Noisy trajectories per pair of the Monte Carlo risk model
+    #[arg(
+        long,
+    )]
+    risk_samples: Option<usize>,
+
+    /// Risk below which a pair of aircraft is not reported
+    #[arg(
+        long,
+    )]
+    risk_cutoff: Option<f64>,
+
+    /// Cell size in metres of the grid aircraft pairs are searched in
+    #[arg(
+        long,
+    )]
+    cell_size: Option<i32>,
+
+    /// Estimator of the conflict probability between aircraft
+    #[arg(
+        long,
+        value_enum,
+    )]
+    risk_model: Option<RiskModel>,
+
+    /// Seed of the Monte Carlo risk noise, for reproducible risk values
+    #[arg(
+        long,
+    )]
+    seed: Option<u64>,
+
+    /// Restore trusted aircraft from this file at startup, saving them back periodically and on exit
+    #[arg(
+        long,
+        value_name = "PATH",
+    )]
+    filter_state: Option<PathBuf>,
+
+    /// Format of the ADS-B input stream
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = InputFormat::Json,
+    )]
+    input_format: InputFormat,
+
+    /// Radar centre, geodetic positions are projected around it
+    #[arg(
+        long,
+        value_name = "LAT,LON",
+        default_value = "0,0",
+        allow_hyphen_values = true,
+    )]
+    center: GeoPoint,
+
+    /// Feeder to connect to (tcp://host:port), a capture file, or - for stdin. Repeatable,
+    /// an optional ID= prefix names the receiver
+    #[arg(
+        long,
+        value_name = "[ID=]URI",
+    )]
+    source: Vec<SourceSpec>,
+
+    /// Accept feeders connecting on this address (e.g. 0.0.0.0:30005). Repeatable
+    #[arg(
+        long,
+        value_name = "[ID=]ADDR",
+        value_parser = SourceSpec::parse_listen,
+    )]
+    listen: Vec<SourceSpec>,
+
+    /// Receive datagrams on this address (e.g. :30003). Repeatable
+    #[arg(
+        long,
+        value_name = "[ID=]ADDR",
+        value_parser = SourceSpec::parse_udp,
+    )]
+    udp: Vec<SourceSpec>,
+
+    /// Window in milliseconds in which reports of an aircraft from other receivers are dropped as duplicates
+    #[arg(
+        long,
+        default_value_t = 1000,
+    )]
+    dedup_window: u64,
+
+    /// Append every received packet to this capture file, gzip-compressed when it ends in .gz
+    #[arg(
+        long,
+        value_name = "PATH",
+    )]
+    record: Option<PathBuf>,
+
+    /// Log rejected input lines with the reason to this file
+    #[arg(
+        long,
+        value_name = "PATH",
+    )]
+    reject_log: Option<PathBuf>,
+
+    /// Age aircraft and filter layers by wall-clock time, ignoring packet timestamps
+    #[arg(
+        long,
+    )]
+    wall_clock: bool,
+
     #[arg(
         short,
         long
     )]
     debug: bool,
+
+    /// Format of the filter command's per-packet records
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+    )]
+    output: OutputFormat,
+
+    /// Print only promoted and trusted packets, as JSON lines, instead of filter records
+    #[arg(
+        long,
+        conflicts_with = "output",
+    )]
+    passthrough: bool,
 }
 
+mod clock;
+mod config;
 mod filter;
+mod input;
 mod simulator;
+#[cfg(test)]
+mod test_util;
 mod tui;
 
-fn process_adsb_stream<F: FnMut(AdsbPacket)>(mut action: F) -> io::Result<()> {
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
-    let mut buf = String::new();
+/// Starts every configured input, stdin when none is given, merging them into a single
+/// de-duplicated packet channel
+fn start_sources(args: &Args, metrics: Arc<SourceMetrics>, trusted: Option<TrustedCheck>, recorder: Option<Arc<Recorder>>) -> io::Result<Receiver<AdsbPacket>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let ingest = Ingest {
+        format: args.input_format,
+        projection: Projection::new(args.center),
+        tx,
+        metrics: metrics.clone(),
+        reject_log: args.reject_log.as_deref().map(RejectLog::create).transpose()?.map(Arc::new),
+        recorder,
+        trusted,
+    };
 
-    while handle.read_line(&mut buf)? > 0 {
-        if let Ok(packet) = serde_json::from_str::<AdsbPacket>(&buf) {
-            action(packet);
-        }
+    let mut sources: Vec<SourceSpec> = args.source.iter()
+        .chain(&args.listen)
+        .chain(&args.udp)
+        .cloned()
+        .collect();
+    if sources.is_empty() {
+        sources.push("-".parse().expect("stdin source"));
+    }
 
-        buf.clear();
+    for source in sources {
+        ingest.spawn(source)?;
     }
 
-    Ok(())
+    Ok(merge::merge(rx, Duration::from_millis(args.dedup_window), metrics, new_clock(args)))
 }
 
-fn run_filter(args: Args) -> io::Result<()> {
-    let mut filter_manager = filter_manager::FilterManager::new();
-    let mut last_prune = Instant::now();
+/// Reads the config file, if any, and applies command line overrides
+fn load_config(args: &Args) -> io::Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let filter = &mut config.filter;
+    filter.backend = args.filter_backend.unwrap_or(filter.backend);
+    filter.threshold = args.filter_threshold.unwrap_or(filter.threshold);
+    filter.promotion_window = args.promotion_window.unwrap_or(filter.promotion_window);
+    filter.target_fpr = args.target_fpr.unwrap_or(filter.target_fpr);
+    filter.partition_size = args.partition_size.unwrap_or(filter.partition_size);
+    filter.growth_factor = args.growth_factor.unwrap_or(filter.growth_factor);
+    filter.tightening_ratio = args.tightening_ratio.unwrap_or(filter.tightening_ratio);
+    filter.fpr_probes = args.fpr_probes.unwrap_or(filter.fpr_probes);
+
+    let conflict = &mut config.conflict;
+    conflict.horizontal_radius = args.horizontal_radius.unwrap_or(conflict.horizontal_radius);
+    conflict.vertical_band = args.vertical_band.unwrap_or(conflict.vertical_band);
+    conflict.lookahead = args.lookahead.unwrap_or(conflict.lookahead);
+    conflict.max_speed = args.max_speed.unwrap_or(conflict.max_speed);
+    conflict.noise_magnitude = args.noise_magnitude.unwrap_or(conflict.noise_magnitude);
+    conflict.risk_model = args.risk_model.unwrap_or(conflict.risk_model);
+    conflict.risk_samples = args.risk_samples.unwrap_or(conflict.risk_samples);
+    conflict.risk_cutoff = args.risk_cutoff.unwrap_or(conflict.risk_cutoff);
+    conflict.cell_size = args.cell_size.unwrap_or(conflict.cell_size);
+    conflict.seed = args.seed.or(conflict.seed);
+
+    config.validate()?;
+    Ok(config)
+}
+
+fn new_filter_manager<T: Clone + Eq + Hash, F: MembershipFilter>(args: &Args, config: &Config, clock: Clock) -> io::Result<FilterManager<T, F>> {
+    match &args.filter_state {
+        Some(path) => FilterManager::load(path, &config.filter, clock),
+        None => Ok(FilterManager::new(&config.filter, clock)),
+    }
+}
+
+fn new_clock(args: &Args) -> Clock {
+    if args.wall_clock { Clock::wall() } else { Clock::new() }
+}
+
+fn new_recorder(args: &Args) -> io::Result<Option<Arc<Recorder>>> {
+    Ok(args.record.as_deref().map(Recorder::create).transpose()?.map(Arc::new))
+}
+
+fn run_filter<F: MembershipFilter>(args: Args, config: Config) -> io::Result<()> {
+    let clock = new_clock(&args);
+    let mut filter_manager = new_filter_manager::<_, F>(&args, &config, clock.clone())?;
+    let mut last_prune = clock.now();
     let prune_interval = Duration::from_secs(5);
+    let source_metrics = Arc::new(SourceMetrics::new());
+    let recorder = new_recorder(&args)?;
+    let mut recording_failed = false;
+
+    let trusted = filter_manager.shared().map(|filter| TrustedCheck { filter, key: |packet| &packet.id });
+    let mut output = FilterOutput::new(io::stdout().lock(), args.output, args.passthrough);
+    // full stats are costly for large filters, they are refreshed every prune
+    let mut stats = filter_manager.stats();
+
+    for packet in start_sources(&args, source_metrics.clone(), trusted, recorder.clone())? {
+        if !recording_failed && let Some(error) = recorder.as_ref().and_then(|r| r.error()) {
+            eprintln!("recording stopped: {error}");
+            recording_failed = true;
+        }
+        if let Some(ts) = packet.ts {
+            clock.observe(packet.receiver.as_deref(), ts);
+        }
 
-    process_adsb_stream(|packet| {
-        if filter_manager.insert(&packet.id) == FilterResult::Pending {
-            println!("NEW:\t{}", &packet.id);
-        } else {
-            println!("MATCH:\t{} (Est. FPR: {:.4}%)", &packet.id, filter_manager.fpr() * 100.0);
+        let result = if packet.trusted { FilterResult::Trusted } else { filter_manager.insert(&packet.id) };
+        let record = FilterRecord {
+            id: &packet.id,
+            callsign: packet.callsign.as_deref(),
+            result,
+            sightings: filter_manager.pending.get(&packet.id).map_or(0, Pending::count),
+            pending: filter_manager.pending.len(),
+            est_fpr: filter_manager.fpr(),
+            observed_fpr: stats.observed_fpr,
+            layers: stats.layer_count,
+            fill_ratio: stats.fill_ratio,
+        };
+        match output.write(&packet, &record) {
+            // the next stage of the pipeline has exited
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            result => result?,
         }
 
-        if Instant::now().duration_since(last_prune) > prune_interval {
+        if args.debug && let Some(ts) = packet.ts {
+            let signal = packet.signal.map(|s| s.to_string()).unwrap_or("-".to_string());
+            eprintln!("PACKET:\t{} receiver ts: {ts:.6}s, signal: {signal}", packet.id);
+        }
+
+        if clock.now().saturating_sub(last_prune) > prune_interval {
             filter_manager.prune(
                 Duration::from_secs(args.max_age)
             );
 
-            last_prune = Instant::now();
+            last_prune = clock.now();
+
+            stats = filter_manager.stats();
+            if args.debug && let Some(observed) = stats.observed_fpr {
+                let layer_fill: Vec<String> = stats.layer_fill.iter().map(|f| format!("{:.1}%", f * 100.0)).collect();
+                eprintln!(
+                    "FPR:\test: {:.4}%, observed: {:.4}%, layer fill: [{}]",
+                    stats.est_fpr * 100.0,
+                    observed * 100.0,
+                    layer_fill.join(", "),
+                );
+            }
+
+            if let Some(path) = &args.filter_state {
+                filter_manager.save(path)?;
+            }
+
+            if args.debug {
+                eprintln!(
+                    "SOURCES:\tduplicates: {}, malformed: {}, implausible: {}",
+                    source_metrics.duplicates.load(Ordering::Relaxed),
+                    source_metrics.malformed.load(Ordering::Relaxed),
+                    source_metrics.implausible.load(Ordering::Relaxed),
+                );
+                for conn in source_metrics.connections() {
+                    eprintln!(
+                        "SOURCE:\t{} connected: {}, connects: {}, packets: {}, bytes: {}, errors: {}",
+                        conn.name,
+                        conn.connected(),
+                        conn.connects.load(Ordering::Relaxed),
+                        conn.packets.load(Ordering::Relaxed),
+                        conn.bytes.load(Ordering::Relaxed),
+                        conn.errors.load(Ordering::Relaxed),
+                    );
+                }
+            }
         }
-    })
+    }
+
+    if let Some(recorder) = &recorder {
+        recorder.flush();
+    }
+    if let Some(path) = &args.filter_state {
+        filter_manager.save(path)?;
+    }
+
+    Ok(())
 }
 
-fn run_simulation(args: Args) -> io::Result<()> {
-    let (tx, rx) = std::sync::mpsc::channel();
+fn run_simulation<F: MembershipFilter>(args: Args, config: Config) -> io::Result<()> {
+    let clock = new_clock(&args);
+    let filter_manager = new_filter_manager::<_, F>(&args, &config, clock.clone())?;
+    let trusted = filter_manager.shared().map(|filter| TrustedCheck { filter, key: track_name });
 
-    thread::spawn(move || {
-        let _ = process_adsb_stream(|packet| {
-            if tx.send(packet).is_err() {
-                return;
-            }
-        });
-    });
+    let source_metrics = Arc::new(SourceMetrics::new());
+    let recorder = new_recorder(&args)?;
+    let rx = start_sources(&args, source_metrics.clone(), trusted, recorder.clone())?;
+
+    let mut app = SimApp::new(args, config, filter_manager, rx, source_metrics, clock)?.with_recorder(recorder);
+    app.run()
+}
+
+fn run_replay<F: MembershipFilter>(args: Args, config: Config) -> io::Result<()> {
+    let path = args.file.clone().expect("replay requires a capture file");
+    let mut decoder = Decoder::new(args.input_format, Projection::new(args.center));
+    let name = format!("replay:{}", path.display());
+    let source_metrics = Arc::new(SourceMetrics::new());
+    let reject_log = args.reject_log.as_deref().map(RejectLog::create).transpose()?;
+    let recording = Recording::read(BufReader::new(record::open_capture(&path)?), &mut decoder, |line, rejection| {
+        source_metrics.count_rejection(&rejection);
+        if let Some(log) = &reject_log {
+            log.log(&name, line, &rejection);
+        }
+    })?;
+
+    let stats = source_metrics.register(name);
+    let control = Arc::new(ReplayControl::new(args.speed, recording.duration()));
+    let (tx, rx) = std::sync::mpsc::channel();
+    replay::spawn(recording, control.clone(), tx, stats);
+    // captures hold every receiver's reports, collapse them like the live run did
+    let dedup_clock = new_clock(&args);
+    let rx = merge::merge(rx, Duration::from_millis(args.dedup_window), source_metrics.clone(), dedup_clock.clone());
 
-    let mut app = SimApp::new(args, rx);
+    let clock = new_clock(&args);
+    let filter_manager = new_filter_manager::<_, F>(&args, &config, clock.clone())?;
+    let mut app = SimApp::new(args, config, filter_manager, rx, source_metrics, clock)?.with_replay(control, dedup_clock);
     app.run()
 }
 
+fn run<F: MembershipFilter>(args: Args, config: Config) -> io::Result<()> {
+    match args.command {
+        Command::Filter => run_filter::<F>(args, config),
+        Command::Simulate => run_simulation::<F>(args, config),
+        Command::Replay => run_replay::<F>(args, config),
+    }
+}
+
 fn main() -> io::Result<()>{
     let args = Args::parse();
+    let config = load_config(&args)?;
 
-    match args.command {
-        Command::Filter => run_filter(args),
-        Command::Simulate => run_simulation(args),
+    match config.filter.backend {
+        FilterBackend::Bloom | FilterBackend::Counting => run::<ScalableBloomFilter>(args, config),
+        FilterBackend::Cuckoo => run::<CuckooFilter>(args, config),
+        FilterBackend::Concurrent => run::<ConcurrentBloomFilter>(args, config),
     }
 }
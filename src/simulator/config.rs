@@ -0,0 +1,108 @@
+use crate::simulator::model::MACH_1;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Estimator of the probability that two aircraft conflict
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+#[value(rename_all = "kebab-case")]
+pub enum RiskModel {
+    /// Gaussian velocity uncertainty integrated over the protected disc at the closest approach
+    #[default]
+    Analytic,
+    /// `risk_samples` noisy trajectories per pair, to validate the analytic model against
+    MonteCarlo,
+}
+
+/// Conflict prediction between aircraft. A conflict is an aircraft entering the protected volume
+/// around another within the lookahead
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConflictConfig {
+    /// Radius of the volume in metres
+    pub horizontal_radius: f64,
+    /// Half height of the volume in feet
+    pub vertical_band: f64,
+    /// Seconds conflicts are predicted ahead
+    pub lookahead: f64,
+    /// Fastest ground speed expected in m/s, aircraft further out than two of them could close in the
+    /// lookahead are not tracked. Defaults to the fastest a packet may report
+    pub max_speed: f64,
+    /// Bound of the uniform error in m/s of each horizontal velocity component
+    pub noise_magnitude: f64,
+    pub risk_model: RiskModel,
+    /// Noisy trajectories per pair of the Monte Carlo model
+    pub risk_samples: usize,
+    /// Risk below which a pair is not reported
+    pub risk_cutoff: f64,
+    /// Cell size in metres of the grid pairs are searched in, only aircraft in neighbouring cells are
+    /// checked against each other
+    pub cell_size: i32,
+    /// Seed of the Monte Carlo velocity noise, random per run when unset
+    pub seed: Option<u64>,
+}
+
+impl Default for ConflictConfig {
+    fn default() -> Self {
+        Self {
+            horizontal_radius: 150.0,
+            vertical_band: 1000.0,
+            lookahead: 30.0,
+            max_speed: MACH_1,
+            noise_magnitude: 5.0,
+            risk_model: RiskModel::Analytic,
+            risk_samples: 1000,
+            risk_cutoff: 0.01,
+            cell_size: 21_000,
+            seed: None,
+        }
+    }
+}
+
+impl ConflictConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("horizontal radius", self.horizontal_radius),
+            ("vertical band", self.vertical_band),
+            ("lookahead", self.lookahead),
+            ("max speed", self.max_speed),
+            ("noise magnitude", self.noise_magnitude),
+        ] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(format!("{name} {value} must be positive"));
+            }
+        }
+        if self.risk_samples == 0 {
+            return Err("risk samples must be at least 1".to_string());
+        }
+        if !(0.0..1.0).contains(&self.risk_cutoff) {
+            return Err(format!("risk cutoff {} must be in [0, 1)", self.risk_cutoff));
+        }
+        // two aircraft closing head on at max speed must still be in neighbouring cells
+        let reach = 2.0 * self.max_speed * self.lookahead + self.horizontal_radius;
+        if (self.cell_size as f64) < reach {
+            return Err(format!(
+                "cell size {} must be at least {reach:.0}, twice the max speed times the lookahead plus the horizontal radius",
+                self.cell_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Ok(()), ConflictConfig::default().validate());
+        assert!(ConflictConfig { lookahead: 0.0, ..ConflictConfig::default() }.validate().is_err());
+        assert!(ConflictConfig { risk_cutoff: 1.0, ..ConflictConfig::default() }.validate().is_err());
+
+        // a grid too fine for the lookahead misses pairs
+        let config = ConflictConfig { cell_size: 10_000, ..ConflictConfig::default() };
+        assert!(config.validate().unwrap_err().contains("20730"));
+        assert_eq!(Ok(()), ConflictConfig { lookahead: 14.0, ..config }.validate());
+    }
+}
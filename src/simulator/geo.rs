@@ -0,0 +1,116 @@
+use crate::simulator::math::Vector2D;
+use std::fmt;
+use std::str::FromStr;
+
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+pub const KNOTS_TO_MS: f64 = 0.514444;
+pub const FEET_TO_M: f64 = 0.3048;
+/// Feet per minute, the unit of reported vertical rates, to m/s
+pub const FPM_TO_MS: f64 = FEET_TO_M / 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        Self { lat, lon }
+    }
+}
+
+impl FromStr for GeoPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lat, lon) = s.split_once(',')
+            .ok_or_else(|| format!("expected LAT,LON, got '{s}'"))?;
+        let lat = lat.trim().parse::<f64>().map_err(|e| format!("invalid latitude: {e}"))?;
+        let lon = lon.trim().parse::<f64>().map_err(|e| format!("invalid longitude: {e}"))?;
+
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(format!("coordinates out of range: {lat},{lon}"));
+        }
+
+        Ok(Self::new(lat, lon))
+    }
+}
+
+impl fmt::Display for GeoPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.lat, self.lon)
+    }
+}
+
+/// Azimuthal equidistant projection about `center` onto the simulator's Cartesian plane
+/// (metres, x east, y north). Distances and bearings from the centre are preserved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Projection {
+    pub center: GeoPoint,
+}
+
+impl Projection {
+    pub fn new(center: GeoPoint) -> Self {
+        Self { center }
+    }
+
+    pub fn project(&self, point: GeoPoint) -> Vector2D {
+        let (lat0, lon0) = (self.center.lat.to_radians(), self.center.lon.to_radians());
+        let (lat, lon) = (point.lat.to_radians(), point.lon.to_radians());
+        let dlon = lon - lon0;
+
+        let cos_c = (lat0.sin() * lat.sin() + lat0.cos() * lat.cos() * dlon.cos()).clamp(-1.0, 1.0);
+        let c = cos_c.acos();
+        let k = if c.abs() < 1e-12 { 1.0 } else { c / c.sin() };
+
+        Vector2D::new(
+            EARTH_RADIUS * k * lat.cos() * dlon.sin(),
+            EARTH_RADIUS * k * (lat0.cos() * lat.sin() - lat0.sin() * lat.cos() * dlon.cos()),
+        )
+    }
+
+    /// Velocity in m/s for a ground speed in knots and a true track in degrees
+    pub fn velocity(ground_speed: f64, track: f64) -> Vector2D {
+        let speed = ground_speed * KNOTS_TO_MS;
+        let track = track.to_radians();
+        Vector2D::new(speed * track.sin(), speed * track.cos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(GeoPoint::new(52.25, -3.5)), "52.25, -3.5".parse());
+        assert!("52.25".parse::<GeoPoint>().is_err());
+        assert!("91,0".parse::<GeoPoint>().is_err());
+    }
+
+    #[test]
+    fn test_project() {
+        let projection = Projection::new(GeoPoint::new(52.0, 4.0));
+        assert_eq!(Vector2D::new(0.0, 0.0), projection.project(GeoPoint::new(52.0, 4.0)));
+
+        // one degree north is ~111km
+        let north = projection.project(GeoPoint::new(53.0, 4.0));
+        assert!(north.x.abs() < 1e-6, "x {}", north.x);
+        assert!((north.y - 111_195.0).abs() < 1.0, "y {}", north.y);
+
+        // distance from the centre is the great circle distance
+        let east = projection.project(GeoPoint::new(52.0, 5.0));
+        assert!((east.distance(Vector2D::new(0.0, 0.0)) - 68_458.0).abs() < 1.0, "{:?}", east);
+        assert!(east.x > 0.0 && east.y > 0.0, "{:?}", east);
+
+        let wrapped = Projection::new(GeoPoint::new(0.0, 179.0)).project(GeoPoint::new(0.0, -179.0));
+        assert!((wrapped.x - 222_390.0).abs() < 1.0, "x {}", wrapped.x);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let v = Projection::velocity(100.0, 90.0);
+        assert!((v.x - 51.4444).abs() < 1e-6 && v.y.abs() < 1e-6, "{:?}", v);
+    }
+}
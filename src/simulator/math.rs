@@ -20,8 +20,7 @@ impl Vector2D {
         (other.x - self.x).powi(2) + (other.y - self.y).powi(2)
     }
 
-    pub fn add_noise(&self, magnitude: f64) -> Self {
-        let mut rng = rand::rng();
+    pub fn add_noise<R: Rng + ?Sized>(&self, magnitude: f64, rng: &mut R) -> Self {
         let dx = rng.random_range(-magnitude..magnitude);
         let dy = rng.random_range(-magnitude..magnitude);
         Self::new(self.x + dx, self.y + dy)
@@ -55,3 +54,231 @@ impl Mul<f64> for Vector2D {
         Self::new(self.x * scalar, self.y * scalar)
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vector3D {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64
+}
+
+impl Vector3D {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn from_horizontal(v: Vector2D, z: f64) -> Self {
+        Self::new(v.x, v.y, z)
+    }
+
+    pub fn horizontal(&self) -> Vector2D {
+        Vector2D::new(self.x, self.y)
+    }
+
+    pub fn dot(&self, other: Vector3D) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Vector3D) -> Self {
+        Self::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    pub fn length_sq(&self) -> f64 {
+        self.dot(*self)
+    }
+
+    pub fn length(&self) -> f64 {
+        self.length_sq().sqrt()
+    }
+
+    /// Unit vector in the same direction, the zero vector stays zero
+    pub fn normalize(&self) -> Self {
+        let length = self.length();
+        if length > 0.0 { *self * (1.0 / length) } else { *self }
+    }
+
+    /// Component of this vector along `onto`
+    pub fn project(&self, onto: Vector3D) -> Self {
+        let length_sq = onto.length_sq();
+        if length_sq > 0.0 { onto * (self.dot(onto) / length_sq) } else { onto }
+    }
+}
+
+impl Add for Vector3D {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+impl Sub for Vector3D {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f64> for Vector3D {
+    type Output = Self;
+    fn mul(self, scalar: f64) -> Self {
+        Self::new(self.x * scalar, self.y * scalar, self.z * scalar)
+    }
+}
+
+/// Closest point of approach of two straight trajectories
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClosestApproach {
+    /// Seconds until the closest approach
+    pub time: f64,
+    pub horizontal_miss: f64,
+    pub vertical_miss: f64,
+}
+
+impl ClosestApproach {
+    /// Closest approach within `[0, horizon]` seconds of a relative position `dp` moving at `dv`
+    pub fn new(dp: Vector3D, dv: Vector3D, horizon: f64) -> Self {
+        // offset along the relative track still to be closed, the rest of `dp` is missed by
+        let along = dp.project(dv);
+        let time = if along.dot(dv) < 0.0 { (along.length() / dv.length()).min(horizon) } else { 0.0 };
+        let miss = dp + dv * time;
+        Self { time, horizontal_miss: miss.horizontal().length_sq().sqrt(), vertical_miss: miss.z.abs() }
+    }
+}
+
+/// Error function after Abramowitz and Stegun 7.1.26, accurate to 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+/// Probability that a 1D Gaussian with standard deviation `sigma`, centred `miss` away from the
+/// centre of a segment of `half_width`, falls inside the segment
+pub fn segment_probability(miss: f64, sigma: f64, half_width: f64) -> f64 {
+    if sigma <= 0.0 {
+        return if miss.abs() < half_width { 1.0 } else { 0.0 };
+    }
+    let scale = sigma * std::f64::consts::SQRT_2;
+    (0.5 * (erf((half_width - miss) / scale) + erf((half_width + miss) / scale))).clamp(0.0, 1.0)
+}
+
+/// Exponentially scaled modified Bessel function of the first kind, `I0(x) e^-|x|`, after
+/// Abramowitz and Stegun 9.8.1 and 9.8.2
+fn bessel_i0e(x: f64) -> f64 {
+    let x = x.abs();
+    if x <= 3.75 {
+        let t = (x / 3.75).powi(2);
+        let i0 = 1.0 + t * (3.5156229 + t * (3.0899424 + t * (1.2067492 + t * (0.2659732 + t * (0.0360768 + t * 0.0045813)))));
+        i0 * (-x).exp()
+    } else {
+        let t = 3.75 / x;
+        (0.39894228 + t * (0.01328592 + t * (0.00225319 + t * (-0.00157565 + t * (0.00916281
+            + t * (-0.02057706 + t * (0.02635537 + t * (-0.01647633 + t * 0.00392377)))))))) / x.sqrt()
+    }
+}
+
+/// Probability that a 2D Gaussian with standard deviation `sigma` on both axes, centred `miss` away
+/// from the centre of a disc of `radius`, falls inside the disc
+pub fn disc_probability(miss: f64, sigma: f64, radius: f64) -> f64 {
+    if sigma <= 0.0 {
+        return if miss < radius { 1.0 } else { 0.0 };
+    }
+
+    // Rice density of the distance from the disc centre, negligible beyond 8 sigma of the miss
+    let var = sigma * sigma;
+    let density = |r: f64| r / var * (-(r - miss).powi(2) / (2.0 * var)).exp() * bessel_i0e(r * miss / var);
+    let (lo, hi) = ((miss - 8.0 * sigma).max(0.0), (miss + 8.0 * sigma).min(radius));
+    if lo >= hi {
+        return 0.0;
+    }
+
+    // Simpson's rule
+    let steps = 64;
+    let h = (hi - lo) / steps as f64;
+    let sum: f64 = (1..steps)
+        .map(|i| density(lo + i as f64 * h) * if i % 2 == 1 { 4.0 } else { 2.0 })
+        .sum();
+    ((density(lo) + sum + density(hi)) * h / 3.0).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_vector3d() {
+        let (x, y) = (Vector3D::new(1.0, 0.0, 0.0), Vector3D::new(0.0, 1.0, 0.0));
+        assert_eq!(0.0, x.dot(y));
+        assert_eq!(Vector3D::new(0.0, 0.0, 1.0), x.cross(y));
+        assert_eq!(Vector3D::new(0.0, 0.0, -1.0), y.cross(x));
+        assert_eq!(0.0, x.cross(y).dot(x));
+
+        let v = Vector3D::new(3.0, 0.0, 4.0);
+        assert_eq!(5.0, v.length());
+        assert!((v.normalize().length() - 1.0).abs() < 1e-12);
+        assert_eq!(Vector3D::new(0.0, 0.0, 1.0), Vector3D::new(0.0, 0.0, 2.5).normalize());
+        assert_eq!(Vector3D::new(0.0, 0.0, 0.0), Vector3D::new(0.0, 0.0, 0.0).normalize());
+        assert_eq!(Vector3D::new(3.0, 0.0, 0.0), v.project(x * 2.0));
+        assert_eq!(Vector3D::new(0.0, 0.0, 0.0), v.project(Vector3D::new(0.0, 0.0, 0.0)));
+        assert_eq!(v, (v - x) + x);
+    }
+
+    #[test]
+    fn test_add_noise() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let v = Vector2D::new(0.0, 100.0);
+        for _ in 0..100 {
+            let noisy = v.add_noise(10.0, &mut rng);
+            assert!((noisy.x - v.x).abs() < 10.0 && (noisy.y - v.y).abs() < 10.0);
+        }
+
+        // the same seed draws the same noise
+        let noisy = |seed| v.add_noise(10.0, &mut StdRng::seed_from_u64(seed));
+        assert_eq!(noisy(7), noisy(7));
+        assert_ne!(noisy(7), noisy(8));
+    }
+
+    #[test]
+    fn test_segment_probability() {
+        // one and two sigma either side
+        assert!((segment_probability(0.0, 10.0, 10.0) - 0.682689).abs() < 1e-5);
+        assert!((segment_probability(0.0, 10.0, 20.0) - 0.954500).abs() < 1e-5);
+        assert!((segment_probability(100.0, 10.0, 100.0) - 0.5).abs() < 1e-6);
+        assert!(segment_probability(300.0, 10.0, 100.0) < 1e-12);
+        assert_eq!(1.0, segment_probability(-50.0, 0.0, 100.0));
+    }
+
+    #[test]
+    fn test_disc_probability() {
+        // centred, the probability is the Rayleigh CDF
+        for (sigma, radius) in [(10.0f64, 5.0f64), (50.0, 150.0), (1.0, 150.0)] {
+            let expected = 1.0 - (-(radius * radius) / (2.0 * sigma * sigma)).exp();
+            let p = disc_probability(0.0, sigma, radius);
+            assert!((p - expected).abs() < 1e-4, "{p} {expected}");
+        }
+        // half the mass lies beyond the edge the miss sits on
+        let p = disc_probability(1000.0, 10.0, 1000.0);
+        assert!((p - 0.5).abs() < 0.01, "{p}");
+        assert!(disc_probability(1000.0, 10.0, 150.0) < 1e-12);
+        assert_eq!(1.0, disc_probability(100.0, 0.0, 150.0));
+        assert_eq!(0.0, disc_probability(200.0, 0.0, 150.0));
+    }
+
+    #[test]
+    fn test_closest_approach() {
+        // 1000 m ahead with 100 m lateral and 50 m vertical offset, closing at 100 m/s
+        let cpa = ClosestApproach::new(Vector3D::new(1000.0, 100.0, 50.0), Vector3D::new(-100.0, 0.0, 0.0), 30.0);
+        assert!((cpa.time - 10.0).abs() < 1e-9);
+        assert!((cpa.horizontal_miss - 100.0).abs() < 1e-9);
+        assert!((cpa.vertical_miss - 50.0).abs() < 1e-9);
+
+        // diverging traffic is closest now, converging traffic at the horizon
+        assert_eq!(0.0, ClosestApproach::new(Vector3D::new(1000.0, 0.0, 0.0), Vector3D::new(10.0, 0.0, 0.0), 30.0).time);
+        assert_eq!(30.0, ClosestApproach::new(Vector3D::new(1000.0, 0.0, 0.0), Vector3D::new(-10.0, 0.0, 0.0), 30.0).time);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod config;
+pub mod geo;
 pub mod math;
 pub mod model;
 pub mod sim_manager;
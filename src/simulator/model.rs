@@ -1,10 +1,12 @@
 use std::collections::VecDeque;
-use std::time::Instant;
-use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::simulator::geo::{GeoPoint, Projection, FEET_TO_M, FPM_TO_MS};
 use crate::simulator::grid::GridCoord;
-use crate::simulator::math::Vector2D;
+use crate::simulator::math::{Vector2D, Vector3D};
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AdsbPacket {
     pub id: String,
     pub callsign: Option<String>,
@@ -12,33 +14,201 @@ pub struct AdsbPacket {
     pub py: f64,
     pub vx: f64,
     pub vy: f64,
+    /// Vertical rate in m/s, positive when climbing
+    #[serde(default)]
+    pub vz: f64,
+    pub alt: f64,
+    /// Receiver timestamp in seconds
+    pub ts: Option<f64>,
+    /// Receiver signal level (0-255)
+    pub signal: Option<u8>,
+    /// Id of the receiver that reported the packet
+    pub receiver: Option<Arc<str>>,
+    /// Set by the ingest thread when it found the aircraft already trusted by a shared filter
+    #[serde(skip)]
+    pub trusted: bool,
+    /// JSON line the packet was read from, which `filter --passthrough` emits verbatim
+    #[serde(skip)]
+    pub raw: Option<String>,
+}
+
+/// Speed of sound at sea level in m/s, no tracked aircraft flies faster
+pub const MACH_1: f64 = 343.0;
+
+impl AdsbPacket {
+    /// Checks the packet describes a physically possible state
+    pub fn validate(&self) -> Result<(), String> {
+        if ![self.px, self.py, self.vx, self.vy, self.vz, self.alt].iter().all(|v| v.is_finite()) {
+            return Err("non-finite position, velocity or altitude".to_string());
+        }
+        let speed = self.vx.hypot(self.vy).hypot(self.vz);
+        if speed > MACH_1 {
+            return Err(format!("speed {speed:.0} m/s above Mach 1"));
+        }
+        if self.alt < 0.0 {
+            return Err(format!("negative altitude {} ft", self.alt));
+        }
+        Ok(())
+    }
+}
+
+/// Packet reported in geodetic coordinates, as carried by real feeds
+#[derive(Debug, Deserialize)]
+pub struct GeodeticPacket {
+    pub id: String,
+    pub callsign: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    /// Ground speed in knots
+    pub gs: f64,
+    /// True track in degrees
+    pub track: f64,
+    /// Vertical rate in ft/min
+    #[serde(default)]
+    pub vs: f64,
     pub alt: f64,
+    pub ts: Option<f64>,
+    pub signal: Option<u8>,
+    pub receiver: Option<Arc<str>>,
+}
+
+impl GeodeticPacket {
+    pub fn project(self, projection: &Projection) -> AdsbPacket {
+        let p = projection.project(GeoPoint::new(self.lat, self.lon));
+        let v = Projection::velocity(self.gs, self.track);
+        AdsbPacket {
+            id: self.id,
+            callsign: self.callsign,
+            px: p.x,
+            py: p.y,
+            vx: v.x,
+            vy: v.y,
+            vz: self.vs * FPM_TO_MS,
+            alt: self.alt,
+            ts: self.ts,
+            signal: self.signal,
+            receiver: self.receiver,
+            trusted: false,
+            raw: None,
+        }
+    }
+}
+
+/// Either packet schema accepted by the JSON input
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum JsonPacket {
+    Cartesian(AdsbPacket),
+    Geodetic(GeodeticPacket),
+}
+
+impl JsonPacket {
+    /// Parses either schema, picking it by the presence of `lat` so errors name the missing field
+    pub fn parse(line: &str) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        if value.get("lat").is_some() {
+            GeodeticPacket::deserialize(value).map(JsonPacket::Geodetic)
+        } else {
+            AdsbPacket::deserialize(value).map(JsonPacket::Cartesian)
+        }
+    }
+
+    pub fn into_packet(self, projection: &Projection) -> AdsbPacket {
+        match self {
+            JsonPacket::Cartesian(packet) => packet,
+            JsonPacket::Geodetic(packet) => packet.project(projection),
+        }
+    }
 }
 
 pub struct Aircraft {
     pub position: Vector2D,
     pub velocity: Vector2D,
     pub altitude: f64,
+    /// m/s, positive when climbing
+    pub vertical_rate: f64,
     history: VecDeque<Vector2D>,
-    pub last_seen: Instant,
+    /// Clock reading of the last update
+    pub last_seen: Duration,
     pub grid_coord: GridCoord,
 
 }
 
 impl Aircraft {
-    pub fn new(position: Vector2D, velocity: Vector2D, altitude: f64, grid_coord: GridCoord) -> Self {
-        Self { position, velocity, altitude, history: VecDeque::with_capacity(32), last_seen: Instant::now(), grid_coord }
+    pub fn new(position: Vector2D, velocity: Vector2D, altitude: f64, vertical_rate: f64, grid_coord: GridCoord, now: Duration) -> Self {
+        Self { position, velocity, altitude, vertical_rate, history: VecDeque::with_capacity(32), last_seen: now, grid_coord }
     }
 
-    pub fn update(&mut self, position: Vector2D, velocity: Vector2D, grid_coord: GridCoord) {
+    /// Position with the altitude in metres
+    pub fn position_3d(&self) -> Vector3D {
+        Vector3D::from_horizontal(self.position, self.altitude * FEET_TO_M)
+    }
+
+    pub fn velocity_3d(&self) -> Vector3D {
+        Vector3D::from_horizontal(self.velocity, self.vertical_rate)
+    }
+
+    pub fn update(&mut self, position: Vector2D, velocity: Vector2D, altitude: f64, vertical_rate: f64, grid_coord: GridCoord, now: Duration) {
         self.history.push_back(self.position);
         self.position = position;
         self.velocity = velocity;
-        self.last_seen = Instant::now();
+        self.altitude = altitude;
+        self.vertical_rate = vertical_rate;
+        self.last_seen = now;
         self.grid_coord = grid_coord;
         if self.history.len() > 32 {
             self.history.pop_front();
         }
 
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_schemas() {
+        let projection = Projection::new(GeoPoint::new(52.0, 4.0));
+
+        let cartesian: JsonPacket = serde_json::from_str(r#"{"id":"A1","callsign":null,"px":1.0,"py":2.0,"vx":3.0,"vy":4.0,"alt":10000}"#).unwrap();
+        let packet = cartesian.into_packet(&projection);
+        assert_eq!((1.0, 2.0, 3.0, 4.0), (packet.px, packet.py, packet.vx, packet.vy));
+
+        let geodetic: JsonPacket = serde_json::from_str(r#"{"id":"A2","callsign":"KLM1","lat":52.0,"lon":4.0,"gs":100,"track":180,"alt":10000,"ts":12.5}"#).unwrap();
+        let packet = geodetic.into_packet(&projection);
+        assert_eq!("A2", packet.id);
+        assert!(packet.px.abs() < 1e-6 && packet.py.abs() < 1e-6, "({}, {})", packet.px, packet.py);
+        assert!(packet.vx.abs() < 1e-6 && (packet.vy + 51.4444).abs() < 1e-6, "({}, {})", packet.vx, packet.vy);
+        assert_eq!(Some(12.5), packet.ts);
+
+        assert!(serde_json::from_str::<JsonPacket>(r#"{"id":"A3","lat":52.0}"#).is_err());
+        let error = JsonPacket::parse(r#"{"id":"A3","lat":52.0}"#).unwrap_err();
+        assert!(error.to_string().contains("missing field `lon`"), "{error}");
+    }
+
+    #[test]
+    fn test_validate() {
+        let packet = AdsbPacket {
+            id: "A1".to_string(),
+            callsign: None,
+            px: 0.0,
+            py: 0.0,
+            vx: 200.0,
+            vy: 100.0,
+            vz: -5.0,
+            alt: 35_000.0,
+            ts: None,
+            signal: None,
+            receiver: None,
+            trusted: false,
+            raw: None,
+        };
+        assert_eq!(Ok(()), packet.validate());
+
+        assert!(AdsbPacket { px: f64::NAN, ..packet.clone() }.validate().is_err());
+        assert!(AdsbPacket { vx: 400.0, ..packet.clone() }.validate().is_err());
+        assert!(AdsbPacket { vz: 400.0, ..packet.clone() }.validate().is_err());
+        assert!(AdsbPacket { alt: -50.0, ..packet }.validate().is_err());
+    }
+}
@@ -1,60 +1,80 @@
+use crate::clock::Clock;
+use crate::filter::hash::{key_bytes, Hash128, Murmur3};
+use crate::simulator::config::{ConflictConfig, RiskModel};
+use crate::simulator::geo::FEET_TO_M;
 use crate::simulator::grid::SpatialGrid;
-use crate::simulator::math::Vector2D;
-use crate::simulator::model::Aircraft;
+use crate::simulator::math::{disc_probability, segment_probability, ClosestApproach, Vector2D, Vector3D};
+use crate::simulator::model::{AdsbPacket, Aircraft};
 use crate::simulator::sim_metrics::SimulationMetrics;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
+
+pub type AircraftPair = (Arc<str>, Arc<str>);
+/// Collision probability and mean time to impact
+pub type Risk = (f64, Option<f64>);
+
+/// Metres apart at which two aircraft have physically collided, or one is a spoofed copy of the other
+const COLLISION_DISTANCE: f64 = 150.0;
 
 pub struct SimManager {
     pub aircraft: HashMap<Arc<str>, Aircraft>,
-    pub collisions: HashMap<(Arc<str>, Arc<str>), (f64, Option<f64>)>,
+    pub collisions: HashMap<AircraftPair, Risk>,
     pub colliding: HashSet<Arc<str>>,
     pub adsb_blacklist: HashSet<Arc<str>>,
     pub spatial_grid: SpatialGrid,
     scale: f64,
+    /// Radar centre on the simulation plane
+    pub center: Vector2D,
     pub radar_range: f64,
     pub metrics: SimulationMetrics,
+    conflict: ConflictConfig,
+    /// Seed every pair's Monte Carlo noise is derived from
+    seed: u64,
+    clock: Clock,
 }
 
 impl SimManager {
-    pub fn new(scale: f64) -> Self {
+    pub fn new(scale: f64, center: Vector2D, conflict: ConflictConfig, clock: Clock) -> Self {
         Self {
             aircraft: HashMap::new(),
             collisions: HashMap::new(),
             colliding: HashSet::new(),
             adsb_blacklist: HashSet::new(),
-            spatial_grid: SpatialGrid::new(15_000),
+            spatial_grid: SpatialGrid::new(conflict.cell_size),
             scale,
+            center,
             radar_range: (scale * 0.2).powi(2),
-            metrics: SimulationMetrics::new()
+            metrics: SimulationMetrics::new(),
+            conflict,
+            seed: conflict.seed.unwrap_or_else(|| rand::rng().random()),
+            clock,
         }
     }
 
-    pub fn handle_update(&mut self, callsign: Arc<str>, px: f64, py: f64, vx: f64, vy: f64, alt: f64) {
-        let p = Vector2D::new(px, py);
-        let v = Vector2D::new(vx, vy);
-        let c = Vector2D::new(0.0, 0.0);
+    pub fn handle_update(&mut self, callsign: Arc<str>, packet: &AdsbPacket) {
+        let p = Vector2D::new(packet.px, packet.py);
+        let v = Vector2D::new(packet.vx, packet.vy);
 
-        let max_speed = 250.0;
-        let lookahead_seconds = 30.0;
-        let safety_buffer = ((max_speed * 2.0) * lookahead_seconds as f64).powi(2);
+        let safety_buffer = ((self.conflict.max_speed * 2.0) * self.conflict.lookahead).powi(2);
 
-        if p.distance_sq(c) > self.radar_range + safety_buffer {
+        if p.distance_sq(self.center) > self.radar_range + safety_buffer {
             self.aircraft.remove(&callsign);
             return;
         }
 
+        let now = self.clock.now();
         self.aircraft.entry(callsign.clone())
-            .and_modify(|a| a.update(p, v, self.spatial_grid.to_grid_coord(p)))
-            .or_insert(Aircraft::new(p, v, alt, self.spatial_grid.to_grid_coord(p)));
+            .and_modify(|a| a.update(p, v, packet.alt, packet.vz, self.spatial_grid.to_grid_coord(p), now))
+            .or_insert(Aircraft::new(p, v, packet.alt, packet.vz, self.spatial_grid.to_grid_coord(p), now));
 
     }
 
     pub fn check_collisions(&mut self) {
-        let c = Vector2D::new(0.0, 0.0);
         self.spatial_grid.clear();
 
         for (id, plane) in &self.aircraft {
@@ -63,9 +83,9 @@ impl SimManager {
 
         let aircraft = &self.aircraft;
 
-        let result: HashMap<(Arc<str>, Arc<str>), (f64, Option<f64>)> = aircraft
+        let result: HashMap<AircraftPair, Risk> = aircraft
             .par_iter()
-            .filter(|(_, plane)| plane.position.distance_sq(c) <= self.radar_range)
+            .filter(|(_, plane)| plane.position.distance_sq(self.center) <= self.radar_range)
             .flat_map(|(id_i, plane)| {
                 self.spatial_grid.get_nearby_ids(id_i, plane.position)
                     .filter(|id_j| id_i < id_j)
@@ -73,90 +93,268 @@ impl SimManager {
                         self.metrics.pairs_checked.fetch_add(1, Ordering::Relaxed);
                         let other = &self.aircraft[id_j];
                         let key = (id_i.clone(), id_j.clone());
-
-                        if plane.altitude == other.altitude {
-                            let (risk, tti) = Self::calculate_risk((plane.position, plane.velocity), (other.position, other.velocity));
-                            (key, (risk, tti))
-                        } else {
-                            (key, (0.0, None))
-                        }
+                        let seed = self.pair_seed(&key);
+                        (key, Self::calculate_risk(plane, other, &self.conflict, seed))
                     })
                     .collect::<Vec<_>>()
             }).collect();
 
         self.collisions.clear();
         result.into_iter()
-            .filter(|(_, (risk, _))| *risk > 0.01)
+            .filter(|(_, (risk, _))| *risk > self.conflict.risk_cutoff)
             .for_each(|(k, risk)| {
                 let plane = &self.aircraft[&k.0];
                 let other = &self.aircraft[&k.1];
                 self.collisions.insert(k.clone(), risk);
                 self.colliding.insert(k.0.clone());
                 self.colliding.insert(k.1.clone());
-                if plane.position.distance_sq(other.position) < 150f64.powi(2) {
+                if (plane.position_3d() - other.position_3d()).length_sq() < COLLISION_DISTANCE.powi(2) {
                     self.adsb_blacklist.insert(k.0.clone());
                     self.adsb_blacklist.insert(k.1.clone());
                 }
             });
     }
 
-    fn calculate_risk(aircraft: (Vector2D, Vector2D), other: (Vector2D, Vector2D)) -> (f64, Option<f64>) {
-        let (p1, v1) = aircraft;
-        let (p2, v2) = other;
+    /// Seed of the pair's noise, independent of the order the pairs are checked in
+    fn pair_seed(&self, (a, b): &AircraftPair) -> u64 {
+        Murmur3.hash128(&key_bytes(&(self.seed, a, b)), 0).0
+    }
 
-        let mut hits = 0;
-        let loops = 1000;
-        let noise_magnitude = 5.0f64;
-        let collision_range = 150.0f64;
-        let collision_range_sq = collision_range.powi(2);
+    /// Position and velocity of `plane` relative to `other`, vertically in metres
+    fn relative(plane: &Aircraft, other: &Aircraft) -> (Vector3D, Vector3D) {
+        (plane.position_3d() - other.position_3d(), plane.velocity_3d() - other.velocity_3d())
+    }
+
+    /// Start of the first conflict within the lookahead, the times the horizontal and vertical
+    /// separation are both inside the protected volume
+    fn time_to_conflict(dp: Vector3D, dv: Vector3D, conflict: &ConflictConfig) -> Option<f64> {
+        let (dp_h, dv_h) = (dp.horizontal(), dv.horizontal());
 
-        if p1.distance_sq(p2) <= collision_range_sq {
+        // |dp + dv t| < radius
+        let a = dv_h.length_sq();
+        let c = dp_h.length_sq() - conflict.horizontal_radius.powi(2);
+        let (h_start, h_end) = if a < 1e-9 {
+            if c >= 0.0 { return None; }
+            (f64::NEG_INFINITY, f64::INFINITY)
+        } else {
+            let b = dp_h.dot(dv_h);
+            let disc = b * b - a * c;
+            if disc <= 0.0 { return None; }
+            let root = disc.sqrt();
+            ((-b - root) / a, (-b + root) / a)
+        };
+
+        // |dz + dvz t| < band
+        let band = conflict.vertical_band * FEET_TO_M;
+        let (v_start, v_end) = if dv.z.abs() < 1e-9 {
+            if dp.z.abs() >= band { return None; }
+            (f64::NEG_INFINITY, f64::INFINITY)
+        } else {
+            let (t1, t2) = ((-band - dp.z) / dv.z, (band - dp.z) / dv.z);
+            (t1.min(t2), t1.max(t2))
+        };
+
+        let start = h_start.max(v_start).max(0.0);
+        let end = h_end.min(v_end).min(conflict.lookahead);
+        (start < end).then_some(start)
+    }
+
+    fn calculate_risk(plane: &Aircraft, other: &Aircraft, conflict: &ConflictConfig, seed: u64) -> Risk {
+        let (dp, dv) = Self::relative(plane, other);
+
+        if Self::time_to_conflict(dp, dv, conflict) == Some(0.0) {
             return (1.0, Some(0.0));
         }
 
+        let cpa = ClosestApproach::new(dp, dv, conflict.lookahead);
+        match conflict.risk_model {
+            RiskModel::Analytic => Self::analytic_risk(dp, dv, cpa, conflict),
+            RiskModel::MonteCarlo => Self::monte_carlo_risk(plane, other, conflict, &mut StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Probability that the horizontal miss at the closest approach falls inside the protected disc,
+    /// with both velocity errors approximated by a Gaussian of the same variance. Before the horizon
+    /// only the error across the relative track moves the miss, along it just shifts the time. The
+    /// vertical rate is taken as reported, so the pair must also be inside the vertical band while it
+    /// would cross the disc with the miss taken out
+    fn analytic_risk(dp: Vector3D, dv: Vector3D, cpa: ClosestApproach, conflict: &ConflictConfig) -> Risk {
+        let across = Vector3D::from_horizontal(dv.horizontal(), 0.0).normalize().cross(Vector3D::new(0.0, 0.0, 1.0));
+        let miss = across * dp.dot(across);
+        if Self::time_to_conflict(dp - miss, dv, conflict).is_none() {
+            return (0.0, None);
+        }
+
+        // uniform errors in [-m, m] have variance m²/3 per axis, the relative velocity sums two
+        let sigma = conflict.noise_magnitude * (2.0f64 / 3.0).sqrt() * cpa.time;
+        let risk = if cpa.time < conflict.lookahead {
+            segment_probability(cpa.horizontal_miss, sigma, conflict.horizontal_radius)
+        } else {
+            // still closing at the horizon
+            disc_probability(cpa.horizontal_miss, sigma, conflict.horizontal_radius)
+        };
+        if risk > 0.0 {
+            (risk, Some(Self::time_to_conflict(dp, dv, conflict).unwrap_or(cpa.time)))
+        } else {
+            (0.0, None)
+        }
+    }
+
+    /// Share of trajectories with noisy horizontal velocities that conflict, the vertical rate is taken
+    /// as reported
+    fn monte_carlo_risk<R: Rng>(plane: &Aircraft, other: &Aircraft, conflict: &ConflictConfig, rng: &mut R) -> Risk {
+        let dp = plane.position_3d() - other.position_3d();
+
+        let mut hits = 0;
         let mut total_hit_time = 0.0;
-        for _ in 0..loops {
-            let dp = p1 - p2;
-            let dv_initial = v1 - v2;
-            let t_cpa = -(dp.dot(dv_initial) / dv_initial.length_sq()).clamp(0.0, 30.0);
-            let scaled_noise = noise_magnitude.max(noise_magnitude * (1.0 + t_cpa * 0.5));
-
-            let v1_new = v1.add_noise(scaled_noise);
-            let v2_new = v2.add_noise(scaled_noise);
-
-            let dv = v1_new - v2_new;
-            let dv_sq = dv.length_sq();
-
-            if dv_sq > 0.001 {
-                let t_cpa = -(dp.dot(dv) / dv_sq);
-
-                if t_cpa > 0.0 && t_cpa < 30.0 {
-                    let closest_dist_sq = (dp + dv * t_cpa).length_sq();
-                    if closest_dist_sq < collision_range_sq {
-                        total_hit_time += t_cpa;
-                        hits += 1;
-                    }
-                }
+        for _ in 0..conflict.risk_samples {
+            let v1_new = Vector3D::from_horizontal(plane.velocity.add_noise(conflict.noise_magnitude, rng), plane.vertical_rate);
+            let v2_new = Vector3D::from_horizontal(other.velocity.add_noise(conflict.noise_magnitude, rng), other.vertical_rate);
+
+            if let Some(t) = Self::time_to_conflict(dp, v1_new - v2_new, conflict) {
+                total_hit_time += t;
+                hits += 1;
             }
         }
 
-        (hits as f64 / loops as f64, if hits > 0 { Some(total_hit_time / hits as f64) } else { None } )
+        (hits as f64 / conflict.risk_samples as f64, if hits > 0 { Some(total_hit_time / hits as f64) } else { None } )
     }
 
-    pub fn prune(&mut self, max_age: Duration, center: Vector2D) {
-        let now = Instant::now();
+    /// Drops stale, out of range and blacklisted aircraft, returning their IDs
+    pub fn prune(&mut self, max_age: Duration) -> Vec<Arc<str>> {
+        let now = self.clock.now();
+        let blacklist = std::mem::take(&mut self.adsb_blacklist);
+        // blacklisted aircraft may already have left the radar range
+        let mut dropped: Vec<Arc<str>> = blacklist.iter()
+            .filter(|id| !self.aircraft.contains_key(*id))
+            .cloned()
+            .collect();
 
         self.aircraft.retain(|k, a| {
-            !self.adsb_blacklist.contains(k) &&
-                now.duration_since(a.last_seen) < max_age &&
-                a.position.distance(center) < self.scale
+            let keep = !blacklist.contains(k) &&
+                now.saturating_sub(a.last_seen) < max_age &&
+                a.position.distance(self.center) < self.scale;
+            if !keep {
+                dropped.push(k.clone());
+            }
+            keep
         });
 
-        self.adsb_blacklist.clear();
-
         self.collisions.retain(|(a, b), _| {
             self.aircraft.contains_key(a) && self.aircraft.contains_key(b)
-        })
+        });
+        dropped
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::geo::FPM_TO_MS;
+    use crate::simulator::grid::GridCoord;
+
+    fn aircraft(x: f64, vx: f64, altitude: f64, vertical_rate: f64) -> Aircraft {
+        Aircraft::new(Vector2D::new(x, 0.0), Vector2D::new(vx, 0.0), altitude, vertical_rate, GridCoord::new(0, 0), Duration::ZERO)
+    }
+
+    #[test]
+    fn test_vertical_band() {
+        for risk_model in [RiskModel::Analytic, RiskModel::MonteCarlo] {
+            let conflict = ConflictConfig { risk_model, ..ConflictConfig::default() };
+            let risk = |a: &Aircraft, b: &Aircraft| SimManager::calculate_risk(a, b, &conflict, 0).0;
+            let a = aircraft(0.0, 100.0, 10_000.0, 0.0);
+
+            // head on, 10 ft apart is no longer treated as safe
+            assert!(risk(&a, &aircraft(2000.0, -100.0, 10_010.0, 0.0)) > 0.9, "{risk_model:?}");
+            // head on but 2000 ft apart and level
+            assert_eq!(0.0, risk(&a, &aircraft(4000.0, -100.0, 12_000.0, 0.0)), "{risk_model:?}");
+            // descending through the other's level as they pass
+            assert!(risk(&a, &aircraft(4000.0, -100.0, 12_000.0, -4000.0 * FPM_TO_MS)) > 0.9, "{risk_model:?}");
+            // reaching the band at the closest approach, inside it for the second half of the crossing
+            assert!(risk(&a, &aircraft(600.0, -100.0, 11_100.0, -2000.0 * FPM_TO_MS)) > 0.9, "{risk_model:?}");
+            // still above the band when the crossing is over
+            assert_eq!(0.0, risk(&a, &aircraft(600.0, -100.0, 11_500.0, -2000.0 * FPM_TO_MS)), "{risk_model:?}");
+        }
+    }
+
+    #[test]
+    fn test_analytic_matches_monte_carlo() {
+        let analytic = ConflictConfig::default();
+        let monte_carlo = ConflictConfig { risk_model: RiskModel::MonteCarlo, ..analytic };
+
+        // converging head on from 2 km at 200 m/s, offset sideways
+        let a = Aircraft::new(Vector2D::new(0.0, 0.0), Vector2D::new(100.0, 0.0), 10_000.0, 0.0, GridCoord::new(0, 0), Duration::ZERO);
+        for offset in [0.0, 100.0, 200.0, 400.0] {
+            let b = Aircraft::new(Vector2D::new(2000.0, offset), Vector2D::new(-100.0, 0.0), 10_000.0, 0.0, GridCoord::new(0, 0), Duration::ZERO);
+            let (p_analytic, _) = SimManager::calculate_risk(&a, &b, &analytic, 0);
+            let (p_mc, _) = SimManager::calculate_risk(&a, &b, &monte_carlo, 0);
+            assert!((p_analytic - p_mc).abs() < 0.1, "offset {offset}: {p_analytic} vs {p_mc}");
+        }
+
+        // the analytic estimate is the same every tick
+        let b = aircraft(2000.0, -100.0, 10_000.0, 0.0);
+        assert_eq!(SimManager::calculate_risk(&a, &b, &analytic, 0), SimManager::calculate_risk(&a, &b, &analytic, 0));
+    }
+
+    #[test]
+    fn test_time_to_conflict() {
+        let conflict = ConflictConfig { horizontal_radius: 300.0, vertical_band: 1000.0, ..ConflictConfig::default() };
+        let a = aircraft(0.0, 0.0, 10_000.0, 0.0);
+        let time = |b: &Aircraft| {
+            let (dp, dv) = SimManager::relative(&a, b);
+            SimManager::time_to_conflict(dp, dv, &conflict)
+        };
+
+        // horizontal entry at 10 s
+        let t = time(&aircraft(1300.0, -100.0, 10_000.0, 0.0)).unwrap();
+        assert!((t - 10.0).abs() < 1e-9, "{t}");
+
+        // inside horizontally from 16 to 28 s, vertically once 500 ft closed at 8 m/s
+        let t = time(&aircraft(1100.0, -50.0, 11_500.0, -8.0)).unwrap();
+        assert!((t - 500.0 * FEET_TO_M / 8.0).abs() < 1e-9, "{t}");
+        // passes horizontally before it descends into the band
+        assert_eq!(None, time(&aircraft(1100.0, -100.0, 11_500.0, -10.0)));
+
+        // overlapping now
+        assert_eq!(Some(0.0), time(&aircraft(50.0, 0.0, 10_500.0, 0.0)));
+    }
+
+    #[test]
+    fn test_blacklist_needs_a_collision() {
+        // 5 NM / 1000 ft separation
+        let conflict = ConflictConfig { horizontal_radius: 9260.0, cell_size: 30_000, ..ConflictConfig::default() };
+        let mut sim = SimManager::new(100_000.0, Vector2D::new(0.0, 0.0), conflict, Clock::new());
+        for (id, px, alt) in [("A", 0.0, 10_000.0), ("B", 1000.0, 10_500.0), ("C", 5000.0, 10_000.0), ("D", 5100.0, 10_100.0)] {
+            let packet: AdsbPacket = serde_json::from_str(&format!(
+                r#"{{"id":"{id}","px":{px},"py":0.0,"vx":0.0,"vy":100.0,"alt":{alt}}}"#
+            )).unwrap();
+            sim.handle_update(Arc::from(id), &packet);
+        }
+        sim.check_collisions();
+
+        // every pair has lost separation, only C and D are close enough to have collided
+        assert_eq!(1.0, sim.collisions[&(Arc::from("A"), Arc::from("B"))].0);
+        assert_eq!(HashSet::from([Arc::from("C"), Arc::from("D")]), sim.adsb_blacklist);
+    }
+
+    #[test]
+    fn test_seeded_risk() {
+        let conflict = ConflictConfig { risk_model: RiskModel::MonteCarlo, seed: Some(42), ..ConflictConfig::default() };
+        let run = || {
+            let mut sim = SimManager::new(100_000.0, Vector2D::new(0.0, 0.0), conflict, Clock::new());
+            for (id, px, vx) in [("A", 0.0, 100.0), ("B", 2000.0, -100.0), ("C", 2100.0, -100.0), ("D", -1500.0, 150.0)] {
+                let packet: AdsbPacket = serde_json::from_str(&format!(
+                    r#"{{"id":"{id}","px":{px},"py":100.0,"vx":{vx},"vy":0.0,"alt":10000}}"#
+                )).unwrap();
+                sim.handle_update(Arc::from(id), &packet);
+            }
+            sim.check_collisions();
+            sim.collisions
+        };
+
+        let collisions = run();
+        assert!(collisions.len() > 2);
+        assert_eq!(collisions, run());
+    }
+}
@@ -0,0 +1,35 @@
+use crate::simulator::model::AdsbPacket;
+use rand::distr::{Alphanumeric, SampleString};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::Arc;
+
+/// `n` random IDs, the same ones for the same `seed`
+pub fn gen_input(n: usize, seed: u64) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..n).map(|_| Alphanumeric.sample_string(&mut rng, 8)).collect()
+}
+
+/// Level aircraft `id` at 10000 ft, without timestamp or receiver
+pub fn packet(id: &str) -> AdsbPacket {
+    AdsbPacket {
+        id: id.to_string(),
+        callsign: None,
+        px: 1.0,
+        py: 2.0,
+        vx: 3.0,
+        vy: 4.0,
+        vz: 0.0,
+        alt: 10_000.0,
+        ts: None,
+        signal: None,
+        receiver: None,
+        trusted: false,
+        raw: None,
+    }
+}
+
+/// `packet` as reported by `receiver`
+pub fn received(id: &str, receiver: &str) -> AdsbPacket {
+    AdsbPacket { receiver: Some(Arc::from(receiver)), ..packet(id) }
+}
@@ -1,4 +1,11 @@
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::filter::bloom_filter::ScalableBloomFilter;
 use crate::filter::filter_manager::{FilterManager, FilterResult};
+use crate::filter::membership::MembershipFilter;
+use crate::input::record::Recorder;
+use crate::input::replay::{ReplayControl, ReplayStatus};
+use crate::input::source_metrics::SourceMetrics;
 use crate::simulator::math::Vector2D;
 use crate::simulator::model::AdsbPacket;
 use crate::simulator::sim_manager::SimManager;
@@ -17,55 +24,121 @@ use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// Name aircraft are tracked and filtered by, the callsign once known
+pub fn track_name(packet: &AdsbPacket) -> &str {
+    packet.callsign.as_deref().unwrap_or(&packet.id)
+}
+
 pub struct AppMetrics {
     pub pairs_checked: u64,
     pub throughput: u64,
     pub total_processing_time: Duration,
 }
 
-pub struct SimApp {
+pub struct SimApp<F: MembershipFilter = ScalableBloomFilter> {
     terminal: DefaultTerminal,
-    filter_manager: FilterManager<Arc<str>>,
+    filter_manager: FilterManager<Arc<str>, F>,
     sim_manager: SimManager,
     receiver: Receiver<AdsbPacket>,
     tick_interval: Duration,
     last_tick: Instant,
     prune_interval: Duration,
-    last_prune: Instant,
+    last_prune: Duration,
+    clock: Clock,
     last_reported_risk: HashMap<(Arc<str>, Arc<str>), f64>,
     metrics: AppMetrics,
+    source_metrics: Arc<SourceMetrics>,
+    replay: Option<Arc<ReplayControl>>,
+    /// Clock of the replay's de-duplication
+    dedup_clock: Option<Clock>,
+    recorder: Option<Arc<Recorder>>,
+    config: Config,
     args: Args,
 }
 
-impl SimApp {
+impl<F: MembershipFilter> SimApp<F> {
 
     const SCALE: f64 = 200_000.0;
+    /// Seconds skipped by the seek keys during replay
+    const SEEK_STEP: f64 = 10.0;
 
-    pub fn new(args: Args, receiver: Receiver<AdsbPacket>) -> SimApp {
-        SimApp {
+    pub fn new(args: Args, config: Config, filter_manager: FilterManager<Arc<str>, F>, receiver: Receiver<AdsbPacket>, source_metrics: Arc<SourceMetrics>, clock: Clock) -> io::Result<SimApp<F>> {
+        Ok(SimApp {
             terminal: ratatui::init(),
-            filter_manager: FilterManager::new(),
-            sim_manager: SimManager::new(Self::SCALE),
+            filter_manager,
+            // packets are projected around the radar centre, so it sits at the origin
+            sim_manager: SimManager::new(Self::SCALE, Vector2D::new(0.0, 0.0), config.conflict, clock.clone()),
             receiver,
             tick_interval: Duration::from_millis(100),
             last_tick: Instant::now(),
             prune_interval: Duration::from_secs(5),
-            last_prune: Instant::now(),
+            last_prune: clock.now(),
+            clock,
             last_reported_risk: HashMap::new(),
             metrics: AppMetrics {
                 pairs_checked: 0,
                 throughput: 0,
                 total_processing_time: Duration::from_secs(0),
             },
+            source_metrics,
+            replay: None,
+            dedup_clock: None,
+            recorder: None,
+            config,
             args,
+        })
+    }
+
+    /// Enables playback controls for a replayed capture, `dedup_clock` restarts with the simulation
+    /// on every rewind
+    pub fn with_replay(mut self, replay: Arc<ReplayControl>, dedup_clock: Clock) -> SimApp<F> {
+        self.replay = Some(replay);
+        self.dedup_clock = Some(dedup_clock);
+        self
+    }
+
+    /// Shows the state of the capture the sources are recording to
+    pub fn with_recorder(mut self, recorder: Option<Arc<Recorder>>) -> SimApp<F> {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Drops all tracks and filter state, and restarts the clock. Used when a replay rewinds
+    fn reset(&mut self) {
+        while self.receiver.try_recv().is_ok() {}
+        self.clock.reset();
+        if let Some(clock) = &self.dedup_clock {
+            clock.reset();
+        }
+        self.filter_manager = FilterManager::new(&self.config.filter, self.clock.clone());
+        self.sim_manager = SimManager::new(Self::SCALE, self.sim_manager.center, self.config.conflict, self.clock.clone());
+        self.last_reported_risk.clear();
+        self.last_prune = self.clock.now();
+    }
+
+    fn handle_replay_key(&self, code: KeyCode) {
+        let Some(replay) = &self.replay else {
+            return;
+        };
+        match code {
+            KeyCode::Char(' ') => replay.toggle_pause(),
+            KeyCode::Char('+') | KeyCode::Char('=') => replay.scale_speed(2.0),
+            KeyCode::Char('-') => replay.scale_speed(0.5),
+            KeyCode::Left => replay.seek_by(-Self::SEEK_STEP),
+            KeyCode::Right => replay.seek_by(Self::SEEK_STEP),
+            _ => {}
         }
     }
 
     pub fn run(&mut self) -> io::Result<()> {
         loop {
+            if self.replay.as_ref().is_some_and(|replay| replay.take_rewind()) {
+                self.reset();
+            }
+
             let mut processed_this_frame = 0;
             while let Ok(packet) = self.receiver.try_recv() {
-                self.handle_packet(packet);
+                self.handle_packet(packet)?;
                 processed_this_frame += 1;
                 if processed_this_frame == 1000 {
                     break;
@@ -80,18 +153,21 @@ impl SimApp {
                 self.last_tick = Instant::now();
             }
 
-            self.terminal.draw(|mut frame| Self::draw(&mut frame, &self.metrics, &self.filter_manager, &self.sim_manager))?;
+            let replay = self.replay.as_ref().map(|replay| replay.status());
+            let recording_error = self.recorder.as_ref().and_then(|recorder| recorder.error());
+            self.terminal.draw(|frame| Self::draw(frame, &self.metrics, &self.filter_manager, &self.sim_manager, &self.source_metrics, replay, recording_error))?;
 
             if crossterm::event::poll(Duration::from_millis(16))? {
                 match crossterm::event::read()? {
-                    Event::Key(key) if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('q') => return Ok(()),
+                    Event::Key(key) if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('q') => return self.quit(),
+                    Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_replay_key(key.code),
                     _ => continue
                 }
             }
         }
     }
 
-    fn draw(frame: &mut Frame, app: &AppMetrics, filter: &FilterManager<Arc<str>>, sim_manager: &SimManager) {
+    fn draw(frame: &mut Frame, app: &AppMetrics, filter: &FilterManager<Arc<str>, F>, sim_manager: &SimManager, sources: &SourceMetrics, replay: Option<ReplayStatus>, recording_error: Option<&str>) {
         let block = Block::new()
             .borders(Borders::ALL)
             .title("ACT Simulator")
@@ -112,19 +188,22 @@ impl SimApp {
         let sidebar_chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
-                Constraint::Length(6),
                 Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(6),
                 Constraint::Min(5),
             ])
             .split(main_layout[1]);
 
-        Self::draw_metrics(frame, sidebar_chunks[0], &app);
-        Self::draw_filter_status(frame, sidebar_chunks[1], &filter, &sim_manager);
-        Self::draw_alerts(frame, sidebar_chunks[2], &sim_manager);
+        Self::draw_metrics(frame, sidebar_chunks[0], app, sources, replay, recording_error);
+        Self::draw_filter_status(frame, sidebar_chunks[1], filter, sim_manager);
+        Self::draw_sources(frame, sidebar_chunks[2], sources);
+        Self::draw_alerts(frame, sidebar_chunks[3], sim_manager);
     }
 
     fn draw_radar(frame: &mut Frame, area: Rect, sim_manager: &SimManager) {
         let range = sim_manager.radar_range.sqrt()*1.1;
+        let center = sim_manager.center;
 
         let canvas = Canvas::default()
             .block(Block::default()
@@ -133,8 +212,8 @@ impl SimApp {
                 .borders(Borders::ALL)
                 .border_type(BorderType::Rounded)
                 .border_style(Style::default()))
-            .x_bounds([-range, range])
-            .y_bounds([-range, range])
+            .x_bounds([center.x - range, center.x + range])
+            .y_bounds([center.y - range, center.y + range])
             .paint(|ctx| {
 
                 for (id, aircraft) in &sim_manager.aircraft {
@@ -148,8 +227,10 @@ impl SimApp {
         frame.render_widget(canvas, area);
     }
 
-    fn draw_metrics(frame: &mut Frame, area: Rect, app: &AppMetrics) {
-        let stats_text = vec![
+    fn draw_metrics(frame: &mut Frame, area: Rect, app: &AppMetrics, sources: &SourceMetrics, replay: Option<ReplayStatus>, recording_error: Option<&str>) {
+        let malformed = sources.malformed.load(Ordering::Relaxed);
+        let implausible = sources.implausible.load(Ordering::Relaxed);
+        let mut stats_text = vec![
             Line::from(vec![
                 Span::styled(" Pairs Checked: ", Style::default().fg(Color::LightBlue)),
                 Span::styled(format!("{}", app.pairs_checked), Style::default()),
@@ -165,8 +246,33 @@ impl SimApp {
                     Style::default().fg(if app.total_processing_time.as_millis() > 100 { Color::Red } else { Color::default()})
                 ),
             ]),
+            Line::from(vec![
+                Span::styled(" Rejected:      ", Style::default().fg(Color::LightBlue)),
+                Span::styled(
+                    format!("{malformed} parse / {implausible} invalid"),
+                    Style::default().fg(if malformed + implausible > 0 { Color::Yellow } else { Color::default() })
+                ),
+            ]),
         ];
 
+        if let Some(replay) = replay {
+            let speed = if replay.speed.is_infinite() { "max".to_string() } else { format!("{}x", replay.speed) };
+            stats_text.push(Line::from(vec![
+                Span::styled(" Replay:        ", Style::default().fg(Color::LightBlue)),
+                Span::styled(
+                    format!("{:.0}/{:.0}s {}{}", replay.position, replay.duration, speed, if replay.paused { " (paused)" } else { "" }),
+                    Style::default().fg(if replay.paused { Color::Yellow } else { Color::default() })
+                ),
+            ]));
+        }
+
+        if let Some(error) = recording_error {
+            stats_text.push(Line::from(vec![
+                Span::styled(" Recording:     ", Style::default().fg(Color::LightBlue)),
+                Span::styled(format!("stopped, {error}"), Style::default().fg(Color::Red)),
+            ]));
+        }
+
         let block = Block::default()
             .title(" System Metrics ")
             .title_style(Style::default().add_modifier(Modifier::BOLD))
@@ -176,7 +282,7 @@ impl SimApp {
         frame.render_widget(Paragraph::new(stats_text).block(block), area);
     }
 
-    fn draw_filter_status(frame: &mut Frame, area: Rect, filter: &FilterManager<Arc<str>>, sim_manager: &SimManager) {
+    fn draw_filter_status(frame: &mut Frame, area: Rect, filter: &FilterManager<Arc<str>, F>, sim_manager: &SimManager) {
         let stats = filter.stats();
 
         let filled = (stats.fill_ratio * 100.0).min(10.0) as usize;
@@ -202,12 +308,23 @@ impl SimApp {
             ]),
             Line::from(vec![
                 Span::styled(" FPR:     ", Style::default().fg(Color::LightBlue)),
-                Span::styled(format!("{:.2}%", stats.est_fpr * 100.0), Style::default()),
+                Span::styled(format!("{:.2}% est", stats.est_fpr * 100.0), Style::default()),
+                Span::styled(
+                    stats.observed_fpr.map(|fpr| format!(", {:.2}% obs", fpr * 100.0)).unwrap_or_default(),
+                    Style::default(),
+                ),
+            ]),
+            Line::from(vec![
+                Span::styled(" Fill:    ", Style::default().fg(Color::LightBlue)),
+                Span::styled(
+                    stats.layer_fill.iter().map(|f| format!("{:.0}%", f * 100.0)).collect::<Vec<_>>().join(" "),
+                    Style::default(),
+                ),
             ]),
         ];
 
         let block = Block::default()
-            .title(" [Filter Status] ") // Brackets in title for that "sketch" look
+            .title(format!(" [Filter Status: {}] ", stats.backend)) // Brackets in title for that "sketch" look
             .title_style(Style::default().add_modifier(Modifier::BOLD))
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded) // Rounded corners look cleaner
@@ -217,6 +334,33 @@ impl SimApp {
 
     }
 
+    fn draw_sources(frame: &mut Frame, area: Rect, sources: &SourceMetrics) {
+        let connections = sources.connections();
+
+        let rows: Vec<Row> = connections.iter().map(|conn| {
+            let connected = conn.connected();
+            Row::new(vec![
+                Cell::from(Span::styled("●", Style::default().fg(if connected { Color::Green } else { Color::Red }))),
+                Cell::from(conn.name.clone()),
+                Cell::from(format!("{}", conn.packets.load(Ordering::Relaxed))),
+                Cell::from(format!("{}K", conn.bytes.load(Ordering::Relaxed) / 1024)),
+                Cell::from(format!("{}", conn.errors.load(Ordering::Relaxed))),
+            ])
+        }).collect();
+
+        let table = Table::new(rows, [
+            Constraint::Length(2),
+            Constraint::Percentage(45),
+            Constraint::Percentage(20),
+            Constraint::Percentage(15),
+            Constraint::Percentage(10),
+        ])
+        .header(Row::new(vec!["", "SOURCE", "PKTS", "RECV", "ERR"]).style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(Block::default().title(format!(" [Sources] dup: {} ", sources.duplicates.load(Ordering::Relaxed))).borders(Borders::ALL).border_type(BorderType::Rounded));
+
+        frame.render_widget(table, area);
+    }
+
     fn draw_alerts(frame: &mut Frame, area: Rect, sim_manager: &SimManager) {
         let mut entries: Vec<_> = sim_manager.collisions.iter().collect();
         entries.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap());
@@ -247,7 +391,7 @@ impl SimApp {
             Row::new(vec![
                 Cell::from(format!("{}<->{}", id1, id2)),
                 Cell::from(format!("{:.0}m", d)),
-                Cell::from(format!("{}", icon)),
+                Cell::from(icon.to_string()),
                 Cell::from(t.map(|x| format!("{:.1}", x)).unwrap_or("".to_string())),
                 Cell::from(format!("{:.0}%", *r * 100.0)),
             ])
@@ -266,33 +410,49 @@ impl SimApp {
         frame.render_widget(table, area);
     }
 
-    pub fn handle_packet(&mut self, packet: AdsbPacket) {
-        let name = Arc::from(packet.callsign.unwrap_or(packet.id));
+    fn quit(&self) -> io::Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.flush();
+        }
+        self.save_filter_state()
+    }
+
+    fn save_filter_state(&self) -> io::Result<()> {
+        match &self.args.filter_state {
+            Some(path) => self.filter_manager.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn handle_packet(&mut self, packet: AdsbPacket) -> io::Result<()> {
+        if let Some(ts) = packet.ts {
+            self.clock.observe(packet.receiver.as_deref(), ts);
+        }
+        let name: Arc<str> = Arc::from(track_name(&packet));
 
         if self.sim_manager.adsb_blacklist.contains(&name) {
-            return;
+            return Ok(());
         }
-        if self.last_prune.elapsed() > self.prune_interval {
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_prune) > self.prune_interval {
 
-            self.sim_manager.prune(
-                Duration::from_secs(10),
-                Vector2D::new(0.0, 0.0)
+            let dropped = self.sim_manager.prune(
+                Duration::from_secs(10)
             );
+            for id in &dropped {
+                self.filter_manager.remove(id);
+            }
             self.filter_manager.prune(
                 Duration::from_secs(self.args.max_age)
             );
             self.last_reported_risk.retain(|k, _| self.sim_manager.collisions.contains_key(k));
 
-            self.last_prune = Instant::now();
+            self.last_prune = now;
+            self.save_filter_state()?;
         }
 
-        if self.filter_manager.insert(&name) != FilterResult::Pending {
-            self.sim_manager.handle_update(
-                Arc::from(name),
-                packet.px, packet.py,
-                packet.vx, packet.vy,
-                packet.alt
-            );
+        if packet.trusted || self.filter_manager.insert(&name) != FilterResult::Pending {
+            self.sim_manager.handle_update(name, &packet);
 
             for (pair, (prob, _)) in &self.sim_manager.collisions {
                 if *prob > 0.0 && self.last_reported_risk.get(pair).map(|x| (x-prob).abs() > 0.05).unwrap_or(true) {
@@ -300,10 +460,11 @@ impl SimApp {
                 }
             }
         }
+        Ok(())
     }
 }
 
-impl Drop for SimApp {
+impl<F: MembershipFilter> Drop for SimApp<F> {
     fn drop(&mut self) {
         ratatui::restore();
     }